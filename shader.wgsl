@@ -21,6 +21,10 @@ struct Camera {
     canvas_mid_delta: vec3<f32>,
     canvas_top_delta: vec3<f32>,
     canvas_right_delta: vec3<f32>,
+    // minimum corner in voxel coordinates and level of the cell to outline,
+    // level 0xffffffff if there is none
+    highlight_pos: vec3<u32>,
+    highlight_level: u32,
 };
 
 @group(0)
@@ -37,9 +41,35 @@ struct ocnode {
 @binding(1)
 var<storage, read> voxels: array<ocnode>;
 
+struct Material {
+    color: vec3<f32>,
+    emission: f32,
+    roughness: f32,
+    transparency: f32,
+}
+
+@group(0)
+@binding(2)
+var<storage, read> palette: array<Material>;
+
 // TODO: advancing two axes at once
 
-fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> vec4<f32> {
+const HIT_NONE: u32 = 0u;
+const HIT_LEAF: u32 = 1u;
+const HIT_STEP_LIMIT: u32 = 2u;
+
+struct RayHit {
+    // one of the HIT_ constants
+    kind: u32,
+    // upper 30 bits of the leaf, only valid for HIT_LEAF
+    material: u32,
+    dist: f32,
+    // face the ray entered through, zero if it started inside the leaf
+    normal: vec3<f32>,
+}
+
+// mirrored by `VoxelBuffer::shader_ray_cast`, keep the two in sync
+fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> RayHit {
     // constants
 
     var total_dist = 0.0;
@@ -71,12 +101,13 @@ fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> vec4<f32> {
     var cur_subnode_idx = tmp_idx.x + tmp_idx.y + tmp_idx.z;
 
     var ascensions_handled: bool = true;
+    var normal = vec3<f32>(0.0);
 
     for (var i = 0; i < 1024; i += 1) {
         // idx & b1 == b0 => void
         // idx & b1 == b1 => not void
         // idx & b11 == b01 => non-leaf
-        // idx & b11 == b11 => leaf
+        // idx & b11 == b11 => leaf, material in the upper 30 bits
 
         // if (i == 5) {
         //     return vec4<f32>(
@@ -93,9 +124,10 @@ fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> vec4<f32> {
         let subnode_mask = (vec3<u32>(cur_subnode_idx) & vec3<u32>(1u, 2u, 4u)) != vec3<u32>(0u);
         let subnode_offset = vec3<f32>(subnode_mask);
 
-        let oc_half = select( cur_ocnode.x0, cur_ocnode.x1, subnode_mask.x );
+        // children are indexed x + 2y + 4z, like `Octant` on the CPU side
+        let oc_half = select( cur_ocnode.x0, cur_ocnode.x1, subnode_mask.z );
         let oc_quarter = select( oc_half.xy, oc_half.zw, subnode_mask.y );
-        let cur_subnode_val = select( oc_quarter.x, oc_quarter.y, subnode_mask.z );
+        let cur_subnode_val = select( oc_quarter.x, oc_quarter.y, subnode_mask.x );
         // let cur_subnode_val = oc_half[(u32(subnode_mask.z) << 1u) + u32(subnode_mask.y)];
 
         // TODO: branch or not
@@ -129,7 +161,7 @@ fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> vec4<f32> {
                 stack[scale] = ((cur_subnode_val >> 2u) << 3u);
             } else {
                 // hit leaf
-                return vec4<f32>(1.0 - total_dist * 0.5, 0.7, total_dist * 0.5, 1.0);
+                return RayHit(HIT_LEAF, cur_subnode_val >> 2u, total_dist, normal);
             }
         } else {
             // subnode void
@@ -151,12 +183,12 @@ fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> vec4<f32> {
 
             cur = fma(vec3<f32>(t), dir, cur);
             total_dist = fma(t, dist_mul, total_dist);
+            normal = -sign(dir) * vec3<f32>(adv_axis);
 
             if ((cur_subnode_idx & idx_offset) != select(0u, idx_offset, neg)) {
                 // ascend, change coordinates
                 if (scale == 0u) {
-                    return vec4<f32>(0.1, 0.1, 0.1, 1.0);
-                    // return vec4<f32>(vec3<f32>(adv_axis), 1.0);
+                    return RayHit(HIT_NONE, 0u, total_dist, normal);
                 }
 
                 scale -= 1u;
@@ -173,7 +205,68 @@ fn ray_cast(origin: vec3<f32>, dir_in: vec3<f32>) -> vec4<f32> {
         }
     }
 
-    return vec4<f32>(0.4, 0.0, 0.7, 1.0);
+    return RayHit(HIT_STEP_LIMIT, 0u, total_dist, normal);
+}
+
+// stand-in for materials missing from the palette
+fn material_tint(material: u32) -> vec3<f32> {
+    let h = material * 0x9e3779b9u;
+    return vec3<f32>(
+        f32((h >> 8u) & 0xffu),
+        f32((h >> 16u) & 0xffu),
+        f32((h >> 24u) & 0xffu),
+    ) / 255.0;
+}
+
+fn get_material(id: u32) -> Material {
+    if (id < arrayLength(&palette)) {
+        return palette[id];
+    }
+    return Material(material_tint(id), 0.0, 1.0, 0.0);
+}
+
+const SKY_COLOR: vec3<f32> = vec3<f32>(0.1, 0.1, 0.1);
+const SUN_DIR: vec3<f32> = vec3<f32>(0.32, 0.87, 0.37);
+
+fn shade(hit: RayHit, dir: vec3<f32>) -> vec3<f32> {
+    let mat = get_material(hit.material);
+
+    let diffuse = max(dot(hit.normal, SUN_DIR), 0.0);
+    let half_dir = normalize(SUN_DIR - dir);
+    let shininess = mix(64.0, 1.0, mat.roughness);
+    let specular = (1.0 - mat.roughness) * pow(max(dot(hit.normal, half_dir), 0.0), shininess);
+
+    let lit = mat.color * (0.3 + 0.7 * diffuse) + vec3<f32>(specular) + mat.color * mat.emission;
+    // TODO: trace through transparent materials instead of blending with the sky
+    return mix(lit, SKY_COLOR, mat.transparency);
+}
+
+const NO_HIGHLIGHT: u32 = 0xffffffffu;
+
+// edge outline of the highlighted cell, 0 outside of it and 1 on its edges
+fn highlight_amount(p: vec3<f32>) -> f32 {
+    if (camera.highlight_level == NO_HIGHLIGHT) {
+        return 0.0;
+    }
+    // the root spans 0..2, so a cell at level l spans 2^-l
+    let size = exp2(-f32(camera.highlight_level));
+    let lo = vec3<f32>(camera.highlight_pos) * exp2(-31.0);
+    let local = (p - lo) / size;
+
+    let eps = 1e-3;
+    if (any(local < vec3<f32>(-eps)) || any(local > vec3<f32>(1.0 + eps))) {
+        return 0.0;
+    }
+    // on a face one coordinate is always at the border, two make an edge
+    let border = min(local, 1.0 - local) < vec3<f32>(0.05);
+    let borders = u32(border.x) + u32(border.y) + u32(border.z);
+    return select(0.25, 1.0, borders >= 2u);
+}
+
+fn is_crosshair(pos: vec2<f32>) -> bool {
+    let aspect = length(camera.canvas_right_delta) / length(camera.canvas_top_delta);
+    let d = abs(vec2<f32>(pos.x * aspect, pos.y));
+    return (d.x < 0.03 && d.y < 0.003) || (d.y < 0.03 && d.x < 0.003);
 }
 
 @fragment
@@ -187,5 +280,23 @@ fn frag_main(
 
     //return vec4(dir, 1.0);
 
-    return ray_cast(camera.origin, dir);
+    let hit = ray_cast(camera.origin, dir);
+    var color: vec3<f32>;
+    switch hit.kind {
+        case HIT_LEAF: {
+            let p = camera.origin + dir * hit.dist;
+            color = mix(shade(hit, dir), vec3<f32>(1.0), highlight_amount(p));
+        }
+        case HIT_NONE: {
+            color = SKY_COLOR;
+        }
+        default: {
+            color = vec3<f32>(0.4, 0.0, 0.7);
+        }
+    }
+
+    if (is_crosshair(pos)) {
+        color = 1.0 - color;
+    }
+    return vec4<f32>(color, 1.0);
 }
@@ -1,6 +1,11 @@
 #![windows_subsystem = "console"]
 
-use std::fs::File;
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    path::PathBuf,
+    str::FromStr,
+};
 
 use winit::event_loop::EventLoop;
 
@@ -9,14 +14,54 @@ use simplelog::{
 };
 
 mod program;
-use program::Program;
+use program::{Options, Program};
+
+const USAGE: &str = "usage:
+    voxelcraft [world] [--import <file>]... [--origin <x,y,z>] [--level <n>]
+        [--material <id>] [--pixel-size <cells>] [--max-height <cells>]
+        [--scale <cells per unit>] [--solid] [--volume-size <x,y,z>]
+        [--threshold <0..1>] [--bands <n>]
+    voxelcraft [world] --export <file> --size <x,y,z> [--origin <x,y,z>] [--level <n>]
+    voxelcraft --recompress <input> [output]";
+
+enum Command {
+    Run(Options),
+    /// rewrite a world file in the current format
+    Recompress {
+        input: PathBuf,
+        output: PathBuf,
+    },
+}
 
 fn main() {
     init_logging();
 
+    let options = match parse_args(std::env::args_os().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Recompress { input, output }) => {
+            if let Err(e) = program::recompress_world(&input, &output) {
+                log::error!("could not recompress {}: {e}", input.display());
+                std::process::exit(1);
+            }
+            return;
+        }
+        Err(e) => {
+            log::error!("{e}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(path) = &options.export {
+        if let Err(e) = program::export_region(&options, path) {
+            log::error!("could not export {}: {e}", path.display());
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let event_loop = EventLoop::new().expect("could not create event loop");
 
-    let mut program = Program::new();
+    let mut program = Program::new(options);
 
     log::info!("running program");
 
@@ -25,6 +70,136 @@ fn main() {
     }
 }
 
+fn parse_args(mut args: impl Iterator<Item = OsString>) -> Result<Command, String> {
+    let mut options = Options {
+        // created on first save
+        world_path: PathBuf::from("world.vxw"),
+        imports: Vec::new(),
+        export: None,
+        export_size: [0; 3],
+        origin: [1.0; 3],
+        level: 12,
+        material: 0,
+        pixel_size: 1.0,
+        max_height: 64,
+        scale: 1.0,
+        solid: false,
+        volume_size: None,
+        threshold: 0.0,
+        bands: 0,
+    };
+
+    let mut world_path = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+        match arg.to_str() {
+            Some("--recompress") => {
+                let input = PathBuf::from(value("--recompress")?);
+                let output = args.next().map_or_else(|| input.clone(), PathBuf::from);
+                return Ok(Command::Recompress { input, output });
+            }
+            Some("--import") => options.imports.push(value("--import")?.into()),
+            Some("--export") => options.export = Some(value("--export")?.into()),
+            Some("--size") => {
+                let value = value("--size")?;
+                options.export_size = parse_list(&value)?
+                    .try_into()
+                    .ok()
+                    .filter(|s: &[u32; 3]| s.iter().all(|&s| s > 0))
+                    .ok_or("--size needs three positive cell counts")?;
+            }
+            Some("--origin") => {
+                let value = value("--origin")?;
+                options.origin = parse_list(&value)?
+                    .try_into()
+                    .ok()
+                    .filter(|c: &[f64; 3]| c.iter().all(|c| (0.0..2.0).contains(c)))
+                    .ok_or("--origin needs three coordinates between 0 and 2")?;
+            }
+            Some("--level") => {
+                options.level = value("--level")?
+                    .to_str()
+                    .and_then(|l| l.parse().ok())
+                    .filter(|l| (1..=31).contains(l))
+                    .ok_or("--level needs a number between 1 and 31")?;
+            }
+            Some("--material") => {
+                // materials are stored in the upper 30 bits of a leaf
+                options.material = value("--material")?
+                    .to_str()
+                    .and_then(|m| m.parse().ok())
+                    .filter(|&m: &u32| m < 1 << 30)
+                    .ok_or("--material needs an ID below 2^30")?;
+            }
+            Some("--pixel-size") => {
+                options.pixel_size = value("--pixel-size")?
+                    .to_str()
+                    .and_then(|p| p.parse().ok())
+                    .filter(|&p: &f64| p > 0.0 && p.is_finite())
+                    .ok_or("--pixel-size needs a positive number")?;
+            }
+            Some("--max-height") => {
+                options.max_height = value("--max-height")?
+                    .to_str()
+                    .and_then(|h| h.parse().ok())
+                    .filter(|&h| h > 0)
+                    .ok_or("--max-height needs a positive cell count")?;
+            }
+            Some("--scale") => {
+                options.scale = value("--scale")?
+                    .to_str()
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&s: &f64| s > 0.0 && s.is_finite())
+                    .ok_or("--scale needs a positive number")?;
+            }
+            Some("--solid") => options.solid = true,
+            Some("--volume-size") => {
+                let value = value("--volume-size")?;
+                options.volume_size = parse_list(&value)?
+                    .try_into()
+                    .ok()
+                    .filter(|s: &[u32; 3]| s.iter().all(|&s| s > 0))
+                    .map(Some)
+                    .ok_or("--volume-size needs three positive sample counts")?;
+            }
+            Some("--threshold") => {
+                options.threshold = value("--threshold")?
+                    .to_str()
+                    .and_then(|t| t.parse().ok())
+                    .filter(|t: &f64| (0.0..=1.0).contains(t))
+                    .ok_or("--threshold needs a number between 0 and 1")?;
+            }
+            Some("--bands") => {
+                options.bands = value("--bands")?
+                    .to_str()
+                    .and_then(|b| b.parse().ok())
+                    .filter(|&b| (1..=256).contains(&b))
+                    .ok_or("--bands needs a number between 1 and 256")?;
+            }
+            Some(flag) if flag.starts_with("--") => return Err(format!("unknown flag {flag}")),
+            _ if world_path.is_none() => world_path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {arg:?}")),
+        }
+    }
+    if let Some(world_path) = world_path {
+        options.world_path = world_path;
+    }
+    if options.export.is_some() && options.export_size == [0; 3] {
+        return Err("--export needs --size".into());
+    }
+    Ok(Command::Run(options))
+}
+
+/// Parses a comma separated list like `1,2.5,3`.
+fn parse_list<T: FromStr>(value: &OsStr) -> Result<Vec<T>, String> {
+    value
+        .to_str()
+        .unwrap_or_default()
+        .split(',')
+        .map(|c| c.trim().parse().map_err(|_| format!("bad number {c:?}")))
+        .collect()
+}
+
 fn init_logging() {
     let config = ConfigBuilder::new()
         //.set_time_format_custom("%T%.6f")
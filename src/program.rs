@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
-    event::{DeviceEvent, ElementState, WindowEvent},
+    event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, WindowEvent},
     event_loop::ActiveEventLoop,
     keyboard::{Key, NamedKey},
     window::Window,
@@ -11,39 +14,259 @@ use winit::{
 
 mod camera;
 mod controller;
+mod formats;
 mod framecounter;
+mod gpubuffer;
+mod palette;
+mod player;
+mod ticker;
 mod voxelbuffer;
 
 use camera::Camera;
 use controller::CameraController;
+use formats::{FormatError, ImportOptions, Placement};
 use framecounter::FrameCounter;
+use palette::Palette;
+use player::Player;
+use ticker::{Ticker, TICK_RATE};
+
+use self::voxelbuffer::{Overwrite, RayHit, VoxelBuffer, WorldFileError, MAX_BUFFER_SIZE};
+
+/// how far away blocks can be broken or placed, in world units
+const REACH: f64 = 1.0;
+
+/// Settings given on the command line, see `main.rs`.
+pub struct Options {
+    /// file the world is loaded from and saved to
+    pub world_path: PathBuf,
+    /// files imported into the world on start
+    pub imports: Vec<PathBuf>,
+    /// file the region at `origin` is exported to instead of starting
+    pub export: Option<PathBuf>,
+    /// extent of the exported region in cells of `level`
+    pub export_size: [u32; 3],
+    /// minimum corner of imported and exported content in world units, 0..2
+    /// on each axis
+    pub origin: [f64; 3],
+    /// octree level of a single imported or exported voxel
+    pub level: u32,
+    /// material of imported content without colours
+    pub material: u32,
+    /// heightmap cells per pixel along x and z
+    pub pixel_size: f64,
+    /// heightmap column height of a white pixel in cells
+    pub max_height: u32,
+    /// mesh cells per model unit
+    pub scale: f64,
+    /// fill the inside of meshes instead of only their surface
+    pub solid: bool,
+    /// samples along x, y and z of raw volumes
+    pub volume_size: Option<[u32; 3]>,
+    /// volume densities above this fraction of the sample range are solid
+    pub threshold: f64,
+    /// number of grey materials volume densities are split into
+    pub bands: u32,
+}
+
+impl Options {
+    fn placement(&self) -> Placement {
+        Placement {
+            origin: self
+                .origin
+                .map(|c| (c * f64::from(1_u32 << 31)) as u32)
+                .into(),
+            level: self.level,
+        }
+    }
+
+    fn import_options(&self) -> ImportOptions {
+        ImportOptions {
+            placement: self.placement(),
+            material: self.material,
+            pixel_size: self.pixel_size,
+            max_height: self.max_height,
+            scale: self.scale,
+            solid: self.solid,
+            volume_size: self.volume_size.map(Into::into),
+            threshold: self.threshold,
+            bands: self.bands,
+        }
+    }
+}
+
+/// Loads the world file, or creates the demo world if there is none, and
+/// applies the imports. The flag is set when the world file exists but could
+/// not be loaded, the demo world then stands in for it.
+fn load_world(options: &Options) -> (VoxelBuffer, Palette, bool) {
+    let world_path = &options.world_path;
+    let mut load_failed = false;
+    let (mut voxel_buffer, mut palette) = if world_path.exists() {
+        VoxelBuffer::load(world_path).unwrap_or_else(|e| {
+            log::error!("could not load {}: {e}", world_path.display());
+            load_failed = true;
+            (VoxelBuffer::new(), Palette::new())
+        })
+    } else {
+        (VoxelBuffer::new(), Palette::new())
+    };
+
+    for path in &options.imports {
+        let result = formats::import(
+            path,
+            &mut voxel_buffer,
+            &mut palette,
+            &options.import_options(),
+        );
+        if let Err(e) = result {
+            log::error!("could not import {}: {e}", path.display());
+        }
+    }
+    (voxel_buffer, palette, load_failed)
+}
+
+/// Writes the region selected by `options` to `path` without opening a
+/// window.
+pub fn export_region(options: &Options, path: &Path) -> Result<(), FormatError> {
+    let (voxel_buffer, palette, _) = load_world(options);
+    formats::export(
+        path,
+        &voxel_buffer,
+        &palette,
+        options.placement(),
+        options.export_size.into(),
+    )
+}
 
-use self::voxelbuffer::VoxelBuffer;
+/// Rewrites a world file with the current format and default compression.
+/// `input` and `output` may be the same file.
+pub fn recompress_world(input: &Path, output: &Path) -> Result<(), WorldFileError> {
+    let (voxel_buffer, palette) = VoxelBuffer::load(input)?;
+    voxel_buffer.save(output, &palette)
+}
 
 pub struct Program<'a> {
     render_ctx: Option<RenderCtx<'a>>,
     fps_counter: FrameCounter,
     controller: CameraController,
+    player: Player,
+    ticker: Ticker,
+    /// interpolation factor of the last frame, see `Camera::render_pos`
+    alpha: f64,
+    voxel_buffer: VoxelBuffer,
+    palette: Palette,
+    /// octree level of the cells broken and placed with the mouse
+    edit_level: u32,
+    /// file the world is loaded from and saved to
+    world_path: PathBuf,
+    /// `world_path` exists but could not be loaded, saving over it needs a
+    /// second press of F5
+    protect_world_file: bool,
 }
 
 struct RenderCtx<'a> {
     window: Arc<Window>,
     surface: wgpu::Surface<'a>,
+    #[allow(dead_code)]
     adapter: wgpu::Adapter,
     device: wgpu::Device,
     queue: wgpu::Queue,
     pipeline: wgpu::RenderPipeline,
     surface_config: wgpu::SurfaceConfiguration,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
     camera: Camera,
 }
 
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    camera: &Camera,
+    voxel_buffer: &VoxelBuffer,
+    palette: &Palette,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("camera bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera.buffer().as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: voxel_buffer.buffer().as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: palette.buffer().as_entire_binding(),
+            },
+        ],
+    })
+}
+
 impl<'a> Program<'a> {
-    pub fn new() -> Self {
+    pub fn new(options: Options) -> Self {
+        let (voxel_buffer, palette, load_failed) = load_world(&options);
+        log::info!("voxel buffer uses {} nodes", voxel_buffer.node_count());
+
+        let mut ticker = Ticker::new(TICK_RATE);
+        ticker.on_tick(|ctx| {
+            ctx.player.update(ctx.camera, ctx.input, ctx.dt, ctx.world);
+        });
+
         Self {
             render_ctx: None,
             fps_counter: FrameCounter::new(0.5),
             controller: CameraController::default(),
+            player: Player::new(),
+            ticker,
+            alpha: 1.0,
+            voxel_buffer,
+            palette,
+            edit_level: 12,
+            world_path: options.world_path,
+            protect_world_file: load_failed,
+        }
+    }
+
+    /// Casts from where the last frame was rendered, so edits land on the
+    /// highlighted cell.
+    fn crosshair_hit(&self) -> Option<RayHit> {
+        let camera = &self.render_ctx.as_ref()?.camera;
+        self.voxel_buffer
+            .raycast(camera.render_pos(self.alpha), camera.dir(), REACH)
+    }
+
+    fn break_block(&mut self) {
+        let Some(hit) = self.crosshair_hit() else {
+            return;
+        };
+        let cell = hit.cell(self.edit_level);
+        if let Err(e) = self.voxel_buffer.remove_voxel(cell, self.edit_level) {
+            log::error!("could not break block: {e}");
+        }
+    }
+
+    fn place_block(&mut self) {
+        let Some(hit) = self.crosshair_hit() else {
+            return;
+        };
+        let Some(cell) = hit.adjacent_at(self.edit_level) else {
+            return;
+        };
+        let eye = self.render_ctx.as_ref().map(|ctx| ctx.camera.pos);
+        if eye.is_some_and(|eye| player::body_overlaps(eye, cell, self.edit_level)) {
+            log::warn!("could not place block: it would overlap the player");
+            return;
+        }
+        let result = self.voxel_buffer.add_voxel_with(
+            cell,
+            self.edit_level,
+            hit.voxel.material,
+            Overwrite::Error,
+        );
+        if let Err(e) = result {
+            log::warn!("could not place block: {e}");
         }
     }
 
@@ -59,7 +282,19 @@ impl<'a> Program<'a> {
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         let render_ctx = self.render_ctx.as_mut().unwrap();
         let delta = self.fps_counter.new_frame();
-        render_ctx.camera.transform(self.controller.cur_dir() * delta);
+        let alpha = self.ticker.advance(
+            delta,
+            self.controller.cur_dir(),
+            &mut render_ctx.camera,
+            &mut self.player,
+            &mut self.voxel_buffer,
+        );
+        self.alpha = alpha;
+        let highlight = self
+            .crosshair_hit()
+            .map(|hit| (hit.cell(self.edit_level), self.edit_level));
+        let render_ctx = self.render_ctx.as_mut().unwrap();
+        render_ctx.camera.highlight = highlight;
 
         if let Some(fps) = self.fps_counter.report() {
             let pos = render_ctx.camera.pos;
@@ -70,6 +305,22 @@ impl<'a> Program<'a> {
                 pos.z
             );
         }
+        let voxels_recreated = self
+            .voxel_buffer
+            .update_buffer(&render_ctx.device, &render_ctx.queue);
+        let palette_recreated = self
+            .palette
+            .update_buffer(&render_ctx.device, &render_ctx.queue);
+        if voxels_recreated || palette_recreated {
+            render_ctx.bind_group = create_bind_group(
+                &render_ctx.device,
+                &render_ctx.bind_group_layout,
+                &render_ctx.camera,
+                &self.voxel_buffer,
+                &self.palette,
+            );
+        }
+
         let output = render_ctx.surface.get_current_texture()?;
         let view = output
             .texture
@@ -96,7 +347,7 @@ impl<'a> Program<'a> {
         });
 
         render_pass.set_pipeline(&render_ctx.pipeline);
-        render_ctx.camera.update_buffer(&render_ctx.queue);
+        render_ctx.camera.update_buffer(&render_ctx.queue, alpha);
         render_pass.set_bind_group(0, &render_ctx.bind_group, &[]);
         render_pass.draw(0..6, 0..1);
         drop(render_pass);
@@ -161,10 +412,12 @@ impl<'a> ApplicationHandler for Program<'a> {
         }))
         .unwrap();
 
-        let mut limits: wgpu::Limits = Default::default();
-        // 0.5 GiB
-        limits.max_buffer_size = 1 << 29;
-        limits.max_storage_buffer_binding_size = 1 << 29;
+        let limits = wgpu::Limits {
+            // 0.5 GiB
+            max_buffer_size: MAX_BUFFER_SIZE,
+            max_storage_buffer_binding_size: MAX_BUFFER_SIZE as u32,
+            ..Default::default()
+        };
 
         let (device, queue) = pollster::block_on(adapter.request_device(
             &wgpu::DeviceDescriptor {
@@ -196,8 +449,6 @@ impl<'a> ApplicationHandler for Program<'a> {
             source: wgpu::ShaderSource::Wgsl(shader_text.into()),
         });
 
-        let mut voxel_buffer = VoxelBuffer::new(&device);
-
         let camera = Camera::new(&device);
 
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -223,24 +474,29 @@ impl<'a> ApplicationHandler for Program<'a> {
                     },
                     count: None,
                 },
-            ],
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("camera bind group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: camera.buffer().as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: voxel_buffer.buffer().as_entire_binding(),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
             ],
         });
 
+        self.voxel_buffer.update_buffer(&device, &queue);
+        self.palette.update_buffer(&device, &queue);
+        let bind_group = create_bind_group(
+            &device,
+            &bind_group_layout,
+            &camera,
+            &self.voxel_buffer,
+            &self.palette,
+        );
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("pipeline layout"),
             bind_group_layouts: &[&bind_group_layout],
@@ -291,9 +547,7 @@ impl<'a> ApplicationHandler for Program<'a> {
             .set_cursor_grab(winit::window::CursorGrabMode::Confined)
             .expect("could not set cursor grab mode");
 
-        camera.update_buffer(&queue);
-        // TODO: only when needed
-        voxel_buffer.update_buffer(&queue);
+        camera.update_buffer(&queue, 1.0);
 
         log::info!("{:#?}", adapter.features());
         log::info!("{:#?}", adapter.get_info());
@@ -302,6 +556,7 @@ impl<'a> ApplicationHandler for Program<'a> {
 
         self.render_ctx = Some(RenderCtx {
                 window,
+                bind_group_layout,
                 bind_group,
                 surface,
                 adapter,
@@ -340,9 +595,76 @@ impl<'a> ApplicationHandler for Program<'a> {
             {
                 event_loop.exit()
             }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.logical_key == Key::Named(NamedKey::F5)
+                    && event.state == ElementState::Pressed =>
+            {
+                if self.protect_world_file {
+                    log::warn!(
+                        "{} could not be loaded, press F5 again to overwrite it",
+                        self.world_path.display()
+                    );
+                    self.protect_world_file = false;
+                } else if let Err(e) = self.voxel_buffer.save(&self.world_path, &self.palette) {
+                    log::error!("could not save {}: {e}", self.world_path.display());
+                }
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.logical_key == Key::Named(NamedKey::F9)
+                    && event.state == ElementState::Pressed =>
+            {
+                // the new buffers have no GPU copy yet, the next frame
+                // uploads them and rebuilds the bind group
+                match VoxelBuffer::load(&self.world_path) {
+                    Ok((voxel_buffer, palette)) => {
+                        self.voxel_buffer = voxel_buffer;
+                        self.palette = palette;
+                        self.protect_world_file = false;
+                    }
+                    Err(e) => log::error!("could not load {}: {e}", self.world_path.display()),
+                }
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.logical_key == Key::Named(NamedKey::F2)
+                    && event.state == ElementState::Pressed =>
+            {
+                self.voxel_buffer.compress_to_dag();
+            }
+            WindowEvent::KeyboardInput { event, .. }
+                if event.logical_key == Key::Character("f".into())
+                    && event.state == ElementState::Pressed
+                    && !event.repeat =>
+            {
+                self.player.toggle_mode();
+            }
             WindowEvent::KeyboardInput { event, .. } => {
                 self.controller.handle_key_event(event);
             }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button,
+                ..
+            } => match button {
+                MouseButton::Left => self.break_block(),
+                MouseButton::Right => self.place_block(),
+                _ => (),
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                let y = match delta {
+                    MouseScrollDelta::LineDelta(_, y) => f64::from(y),
+                    MouseScrollDelta::PixelDelta(pos) => pos.y,
+                };
+                // touchpads report zero deltas at the end of a gesture
+                if y != 0.0 {
+                    // scrolling up selects bigger cells
+                    self.edit_level = if y > 0.0 {
+                        self.edit_level.saturating_sub(1).max(1)
+                    } else {
+                        (self.edit_level + 1).min(24)
+                    };
+                    log::info!("edit level {}", self.edit_level);
+                }
+            }
             // TODO: scale factor
             WindowEvent::Resized(new_size) => {
                 self.resize(new_size);
@@ -357,11 +679,8 @@ impl<'a> ApplicationHandler for Program<'a> {
         _device_id: winit::event::DeviceId,
         event: DeviceEvent,
     ) {
-        match event {
-            DeviceEvent::MouseMotion { delta } => {
-                self.render_ctx.as_mut().unwrap().camera.rotate(delta);
-            }
-            _ => (),
+        if let DeviceEvent::MouseMotion { delta } = event {
+            self.render_ctx.as_mut().unwrap().camera.rotate(delta);
         }
     }
 }
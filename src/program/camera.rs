@@ -9,13 +9,18 @@ pub struct Camera {
     /// radians between 0 and 2pi
     yaw: f64,
     pub pos: Vector3<f64>,
+    /// position at the start of the current tick, rendering interpolates
+    /// between it and `pos`
+    prev_pos: Vector3<f64>,
+    /// minimum corner and level of the cell outlined by the shader
+    pub highlight: Option<(Vector3<u32>, u32)>,
 }
 
 impl Camera {
     pub fn new(device: &wgpu::Device) -> Self {
         let buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("camera canvas buffer"),
-            size: 16 * std::mem::size_of::<f32>() as u64,
+            size: std::mem::size_of::<CameraData>() as u64,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
@@ -25,13 +30,25 @@ impl Camera {
             pitch: -std::f64::consts::FRAC_PI_4, // 0.0,
             yaw: std::f64::consts::FRAC_PI_4,    // 0.0,
             pos: Vector3::repeat(0.3),
+            prev_pos: Vector3::repeat(0.3),
+            highlight: None,
         }
     }
 
+    pub fn begin_tick(&mut self) {
+        self.prev_pos = self.pos;
+    }
+
     pub fn transform(&mut self, delta: Vector3<f64>) {
+        self.pos += self.to_world(delta);
+    }
+
+    /// Rotates a camera relative vector by the yaw only, keeping horizontal
+    /// vectors horizontal.
+    pub fn to_world(&self, delta: Vector3<f64>) -> Vector3<f64> {
         let yaw_rot = Rotation3::from_scaled_axis(self.yaw * Vector3::y());
 
-        self.pos += yaw_rot * delta;
+        yaw_rot * delta
     }
 
     pub fn rotate(&mut self, mut delta: (f64, f64)) {
@@ -42,20 +59,39 @@ impl Camera {
         self.yaw = (self.yaw + delta.0) % (2.0 * PI);
     }
 
-    pub fn update_buffer(&self, queue: &wgpu::Queue) {
+    fn rotation(&self) -> Rotation3<f64> {
         let pitch_rot = Rotation3::from_scaled_axis(self.pitch * Vector3::x());
         let yaw_rot = Rotation3::from_scaled_axis(self.yaw * Vector3::y());
-        let rot = yaw_rot * pitch_rot;
+        yaw_rot * pitch_rot
+    }
+
+    /// unit vector through the middle of the screen
+    pub fn dir(&self) -> Vector3<f64> {
+        self.rotation() * Vector3::z()
+    }
+
+    /// Position a frame is rendered from, `alpha` is how far it lies between
+    /// the previous and the current tick position.
+    pub fn render_pos(&self, alpha: f64) -> Vector3<f64> {
+        self.prev_pos.lerp(&self.pos, alpha)
+    }
+
+    pub fn update_buffer(&self, queue: &wgpu::Queue, alpha: f64) {
+        let rot = self.rotation();
+        let pos = self.render_pos(alpha);
 
         let dir = rot * Vector3::z();
         let r = rot * Vector3::x() * 1280.0 / 1500.0;
         let u = rot * Vector3::y() * 720.0 / 1500.0;
+        // u32::MAX level means nothing is highlighted
+        let (highlight_pos, highlight_level) =
+            self.highlight.unwrap_or((Vector3::zeros(), u32::MAX));
 
         let data = CameraData {
             origin: Vec3F32 {
-                x: self.pos.x as f32,
-                y: self.pos.y as f32,
-                z: self.pos.z as f32,
+                x: pos.x as f32,
+                y: pos.y as f32,
+                z: pos.z as f32,
                 pad: 0.0,
             },
             canvas_mid_delta: Vec3F32 {
@@ -76,6 +112,8 @@ impl Camera {
                 z: r.z as f32,
                 pad: 0.0,
             },
+            highlight_pos: highlight_pos.into(),
+            highlight_level,
         };
         queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&data));
     }
@@ -95,10 +133,12 @@ struct Vec3F32 {
 }
 
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-#[repr(C, align(64))]
+#[repr(C, align(16))]
 struct CameraData {
     origin: Vec3F32,
     canvas_mid_delta: Vec3F32,
     canvas_top_delta: Vec3F32,
     canvas_right_delta: Vec3F32,
+    highlight_pos: [u32; 3],
+    highlight_level: u32,
 }
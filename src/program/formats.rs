@@ -0,0 +1,196 @@
+//! Importers and exporters for external voxel and mesh formats.
+
+use std::{io, path::Path};
+
+use nalgebra::Vector3;
+
+use super::{
+    palette::{Material, Palette},
+    voxelbuffer::{VoxelBuffer, VoxelError},
+};
+
+mod cells;
+mod heightmap;
+mod obj;
+mod points;
+mod surface;
+mod volume;
+mod vox;
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    /// the file does not follow its format, with a description of the problem
+    Invalid(String),
+    /// the file extension does not belong to a supported format
+    UnknownFormat,
+    /// the world content cannot be represented in the format
+    Unsupported(String),
+    Voxel(VoxelError),
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "io error: {e}"),
+            FormatError::Invalid(reason) => write!(f, "invalid file: {reason}"),
+            FormatError::UnknownFormat => write!(f, "unknown file format"),
+            FormatError::Unsupported(reason) => write!(f, "cannot export: {reason}"),
+            FormatError::Voxel(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(e) => Some(e),
+            FormatError::Voxel(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            FormatError::Invalid("unexpected end of file".into())
+        } else {
+            FormatError::Io(e)
+        }
+    }
+}
+
+impl From<VoxelError> for FormatError {
+    fn from(e: VoxelError) -> Self {
+        FormatError::Voxel(e)
+    }
+}
+
+/// Where imported content ends up in the world.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    /// minimum corner in voxel coordinates
+    pub origin: Vector3<u32>,
+    /// octree level of a single imported voxel
+    pub level: u32,
+}
+
+impl Placement {
+    /// Side of one imported voxel in voxel coordinates.
+    fn cell_side(&self) -> u32 {
+        1 << (31 - self.level)
+    }
+
+    /// Voxel coordinates of the imported cell at `cell`, or `None` if it lies
+    /// outside the octree.
+    fn cell_pos(&self, cell: Vector3<u32>) -> Option<Vector3<u32>> {
+        let side = u64::from(self.cell_side());
+        let pos = self.origin.cast::<u64>() + cell.cast::<u64>() * side;
+        pos.iter()
+            .all(|&c| c + side <= 1 << 32)
+            .then(|| pos.map(|c| c as u32))
+    }
+}
+
+/// Settings for `import`. Formats ignore the ones that do not apply to them.
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions {
+    pub placement: Placement,
+    /// material of formats without colours
+    pub material: u32,
+    /// heightmaps: cells per image pixel along x and z
+    pub pixel_size: f64,
+    /// heightmaps: column height of a white pixel in cells
+    pub max_height: u32,
+    /// meshes and point clouds: cells per model unit
+    pub scale: f64,
+    /// meshes: fill the inside instead of only the surface
+    pub solid: bool,
+    /// raw volumes: samples along x, y and z
+    pub volume_size: Option<Vector3<u32>>,
+    /// volumes: densities above this fraction of the sample range are solid
+    pub threshold: f64,
+    /// volumes: number of grey materials the solid densities are split
+    /// into, 0 to use `material` for all of them
+    pub bands: u32,
+}
+
+/// Imports `path` into the world, picking the format by file extension.
+/// Directories are read as stacks of volume slices. Returns the number of
+/// voxels or leaves written.
+pub fn import(
+    path: &Path,
+    world: &mut VoxelBuffer,
+    palette: &mut Palette,
+    options: &ImportOptions,
+) -> Result<u64, FormatError> {
+    if path.is_dir() {
+        return volume::read_slices(path)?.insert(world, palette, options);
+    }
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("vox") => vox::read(path)?.insert(world, palette, options.placement),
+        Some("pgm") => heightmap::read_pgm(path)?.insert(world, options),
+        Some("png") => heightmap::read_png(path)?.insert(world, options),
+        Some("obj") => obj::read(path)?.insert(world, options),
+        Some("ply") => points::read_ply(path)?.insert(world, palette, options),
+        Some("xyz") => points::read_xyz(path)?.insert(world, palette, options),
+        Some("raw") => volume::read_raw(path, options.volume_size)?.insert(world, palette, options),
+        _ => Err(FormatError::UnknownFormat),
+    }
+}
+
+/// Exports the box of `size` cells at `placement` to `path`, picking the
+/// format by file extension.
+pub fn export(
+    path: &Path,
+    world: &VoxelBuffer,
+    palette: &Palette,
+    placement: Placement,
+    size: Vector3<u32>,
+) -> Result<(), FormatError> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("vox") => vox::VoxScene::extract(world, palette, placement, size)?.write(path),
+        Some("obj") => surface::Surface::extract(world, placement, size)?.write_obj(path, palette),
+        Some("stl") => surface::Surface::extract(world, placement, size)?.write_stl(path),
+        _ => Err(FormatError::UnknownFormat),
+    }
+}
+
+/// Converts an 8 bit sRGB colour to the linear colour of a new material.
+fn srgb_material(rgb: [u8; 3]) -> Material {
+    let linear = |c: u8| {
+        let c = f32::from(c) / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    Material::new(rgb.map(linear))
+}
+
+/// 8 bit sRGB colour and opacity of a material, the inverse of
+/// `srgb_material`.
+fn material_srgb(material: &Material) -> [u8; 4] {
+    let srgb = |c: f32| {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+        (c * 255.0).round() as u8
+    };
+    let [r, g, b] = material.color.map(srgb);
+    let a = ((1.0 - material.transparency.clamp(0.0, 1.0)) * 255.0).round() as u8;
+    [r, g, b, a]
+}
@@ -0,0 +1,213 @@
+//! Writing dense cell data into the octree. Regions that are uniform over an
+//! aligned cube become a single leaf instead of one leaf per cell.
+
+use nalgebra::Vector3;
+
+use super::{FormatError, Placement};
+use crate::program::voxelbuffer::{Overwrite, VoxelBuffer, VoxelError};
+
+/// Largest grid the importers allocate, in cells.
+pub const MAX_GRID_CELLS: u64 = 1 << 31;
+
+/// Block state meaning no cell of the block is solid.
+const EMPTY: u32 = u32::MAX;
+/// Block state meaning the cells of the block differ.
+const MIXED: u32 = u32::MAX - 1;
+
+/// One bit per cell of a box.
+pub struct BitGrid {
+    size: Vector3<u32>,
+    bits: Vec<u64>,
+}
+
+impl BitGrid {
+    pub fn new(size: Vector3<u32>) -> Result<Self, FormatError> {
+        let cells = size.iter().map(|&s| u64::from(s)).product::<u64>();
+        if cells > MAX_GRID_CELLS {
+            return Err(FormatError::Unsupported(format!(
+                "{}x{}x{} cells are too many, use a coarser level or scale",
+                size.x, size.y, size.z
+            )));
+        }
+        Ok(Self {
+            size,
+            bits: vec![0; cells.div_ceil(64) as usize],
+        })
+    }
+
+    pub fn size(&self) -> Vector3<u32> {
+        self.size
+    }
+
+    fn index(&self, p: Vector3<u32>) -> usize {
+        let s = self.size.map(|s| s as usize);
+        p.x as usize + s.x * (p.y as usize + s.y * p.z as usize)
+    }
+
+    pub fn get(&self, p: Vector3<u32>) -> bool {
+        let i = self.index(p);
+        self.bits[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    pub fn set(&mut self, p: Vector3<u32>) {
+        let i = self.index(p);
+        self.bits[i / 64] |= 1 << (i % 64);
+    }
+}
+
+/// Block states of absolutely aligned cubes of one size.
+struct Level {
+    /// absolute index of the first block on each axis
+    first: Vector3<u32>,
+    len: Vector3<u32>,
+    states: Vec<u32>,
+}
+
+impl Level {
+    fn get(&self, idx: Vector3<u32>) -> u32 {
+        // indices below `first` wrap around to beyond `len`
+        let rel = idx.zip_map(&self.first, |i, f| i.wrapping_sub(f));
+        if (0..3).any(|a| rel[a] >= self.len[a]) {
+            return EMPTY;
+        }
+        let len = self.len.map(|l| l as usize);
+        self.states[rel[0] as usize + len.x * (rel[1] as usize + len.y * rel[2] as usize)]
+    }
+}
+
+/// Block states for every cube size from 4 cells up. Smaller blocks are
+/// cheap enough to compute from the cells when needed.
+struct Pyramid<F> {
+    cell: F,
+    /// absolute cell index of the first cell
+    base: Vector3<u32>,
+    size: Vector3<u32>,
+    /// level `k` at index `k - 2`
+    levels: Vec<Level>,
+}
+
+impl<F: Fn(Vector3<u32>) -> Option<u32>> Pyramid<F> {
+    fn state(&self, k: u32, idx: Vector3<u32>) -> u32 {
+        match k {
+            0 => {
+                let local = idx.zip_map(&self.base, |i, b| i.wrapping_sub(b));
+                if (0..3).any(|a| local[a] >= self.size[a]) {
+                    return EMPTY;
+                }
+                (self.cell)(local).unwrap_or(EMPTY)
+            }
+            1 => self.combine(k, idx),
+            _ => match self.levels.get(k as usize - 2) {
+                Some(level) => level.get(idx),
+                // above the top level only the block containing the whole
+                // region is not empty
+                None if idx == self.base.map(|b| b >> k) => {
+                    let top = self.levels.len() as u32 + 1;
+                    self.state(top, self.base.map(|b| b >> top))
+                }
+                None => EMPTY,
+            },
+        }
+    }
+
+    /// State of a block from the states of its eight children.
+    fn combine(&self, k: u32, idx: Vector3<u32>) -> u32 {
+        let first = self.state(k - 1, idx * 2);
+        for octant in 1..8 {
+            let child = Vector3::new(octant & 1, (octant >> 1) & 1, (octant >> 2) & 1);
+            if self.state(k - 1, idx * 2 + child) != first {
+                return MIXED;
+            }
+        }
+        first
+    }
+
+    fn single_block(&self, k: u32) -> bool {
+        (0..3).all(|a| self.base[a] >> k == (self.base[a] + self.size[a] - 1) >> k)
+    }
+}
+
+/// Writes the `size` cells at `placement` into the world. `cell` returns the
+/// material of a cell relative to the origin, `None` leaves it untouched.
+/// Returns the number of leaves inserted.
+pub fn insert_cells(
+    world: &mut VoxelBuffer,
+    placement: Placement,
+    size: Vector3<u32>,
+    cell: impl Fn(Vector3<u32>) -> Option<u32>,
+) -> Result<u64, FormatError> {
+    if size.iter().any(|&s| s == 0) {
+        return Ok(0);
+    }
+    if placement.cell_pos(size.map(|s| s - 1)).is_none() {
+        return Err(FormatError::Invalid(
+            "imported data does not fit into the world".into(),
+        ));
+    }
+    let shift = 31 - placement.level;
+    let mut pyramid = Pyramid {
+        cell,
+        base: placement.origin.map(|c| c >> shift),
+        size,
+        levels: Vec::new(),
+    };
+
+    let mut k = 2;
+    while !pyramid.single_block(k - 1) {
+        let first = pyramid.base.map(|b| b >> k);
+        let last = pyramid.base.zip_map(&size, |b, s| (b + s - 1) >> k);
+        let len = last - first + Vector3::repeat(1);
+        let mut states = Vec::with_capacity(len.iter().map(|&l| l as usize).product());
+        for z in 0..len.z {
+            for y in 0..len.y {
+                for x in 0..len.x {
+                    states.push(pyramid.combine(k, first + Vector3::new(x, y, z)));
+                }
+            }
+        }
+        pyramid.levels.push(Level { first, len, states });
+        k += 1;
+    }
+
+    let mut top = 0;
+    while !pyramid.single_block(top) {
+        top += 1;
+    }
+    let mut leaves = 0;
+    write_block(
+        world,
+        &pyramid,
+        placement.level,
+        top,
+        pyramid.base.map(|b| b >> top),
+        &mut leaves,
+    )?;
+    Ok(leaves)
+}
+
+fn write_block<F: Fn(Vector3<u32>) -> Option<u32>>(
+    world: &mut VoxelBuffer,
+    pyramid: &Pyramid<F>,
+    level: u32,
+    k: u32,
+    idx: Vector3<u32>,
+    leaves: &mut u64,
+) -> Result<(), VoxelError> {
+    match pyramid.state(k, idx) {
+        EMPTY => Ok(()),
+        // the block of the whole octree is no leaf even if it is uniform, it
+        // is written through the eight root children
+        material if material != MIXED && k <= level => {
+            let pos = idx.map(|c| ((u64::from(c) << k) << (31 - level)) as u32);
+            *leaves += 1;
+            world.add_voxel_with(pos, level - k, material, Overwrite::Replace)
+        }
+        _ => {
+            for octant in 0..8 {
+                let child = Vector3::new(octant & 1, (octant >> 1) & 1, (octant >> 2) & 1);
+                write_block(world, pyramid, level, k - 1, idx * 2 + child, leaves)?;
+            }
+            Ok(())
+        }
+    }
+}
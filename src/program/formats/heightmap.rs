@@ -0,0 +1,447 @@
+//! Grayscale heightmaps from binary or ASCII PGM and from PNG images.
+//!
+//! Each pixel becomes a solid column standing on the placement origin, the
+//! brightest possible value reaching `ImportOptions::max_height` cells.
+//! Columns are not written cell by cell: cubes that lie fully below the
+//! surface are inserted as single large leaves.
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use nalgebra::Vector3;
+
+use super::{cells::MAX_GRID_CELLS, FormatError, ImportOptions};
+use crate::program::voxelbuffer::{Overwrite, VoxelBuffer, VoxelError};
+
+pub struct Heightmap {
+    pub(super) width: usize,
+    pub(super) height: usize,
+    /// row major samples, 0 is black and 1 is white
+    pub(super) samples: Vec<f32>,
+}
+
+impl Heightmap {
+    /// Sample at a fractional pixel position, interpolated between the four
+    /// nearest pixels.
+    fn sample(&self, x: f64, y: f64) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f64);
+        let y = y.clamp(0.0, (self.height - 1) as f64);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = ((x - x0 as f64) as f32, (y - y0 as f64) as f32);
+        let at = |x: usize, y: usize| self.samples[y * self.width + x];
+        let top = at(x0, y0) + (at(x1, y0) - at(x0, y0)) * fx;
+        let bottom = at(x0, y1) + (at(x1, y1) - at(x0, y1)) * fx;
+        top + (bottom - top) * fy
+    }
+}
+
+pub fn read_pgm(path: &Path) -> Result<Heightmap, FormatError> {
+    parse_pgm(&std::fs::read(path)?)
+}
+
+/// Whitespace separated PGM header fields, comments run to the line end.
+struct Tokens<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self) -> &'a str {
+        loop {
+            match self.data.get(self.pos) {
+                Some(b'#') => {
+                    while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        while self
+            .data
+            .get(self.pos)
+            .is_some_and(|b| !b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.pos]).unwrap_or_default()
+    }
+
+    fn number(&mut self, name: &str) -> Result<usize, FormatError> {
+        self.next()
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| FormatError::Invalid(format!("pgm: bad {name}")))
+    }
+}
+
+pub fn parse_pgm(data: &[u8]) -> Result<Heightmap, FormatError> {
+    let invalid = |reason: &str| FormatError::Invalid(format!("pgm: {reason}"));
+
+    let mut tokens = Tokens { data, pos: 0 };
+    let binary = match tokens.next() {
+        "P5" => true,
+        "P2" => false,
+        _ => return Err(invalid("not a grayscale pgm")),
+    };
+    let width = tokens.number("width")?;
+    let height = tokens.number("height")?;
+    let max = tokens.number("maximum value")?;
+    if max > u16::MAX as usize {
+        return Err(invalid("maximum value above 65535"));
+    }
+
+    let count = width
+        .checked_mul(height)
+        .ok_or_else(|| invalid("image too large"))?;
+    // check the payload before allocating for it, ASCII samples take at
+    // least one byte each
+    let bytes_per_sample = if max < 256 { 1 } else { 2 };
+    let payload = data.len() - tokens.pos;
+    let needed = if binary {
+        count
+            .checked_mul(bytes_per_sample)
+            .and_then(|n| n.checked_add(1))
+    } else {
+        Some(count)
+    };
+    if needed.is_none_or(|n| n > payload) {
+        return Err(invalid("pixel data is truncated"));
+    }
+
+    let mut samples = Vec::with_capacity(count);
+    if binary {
+        // a single whitespace byte separates the header from the pixels
+        let start = tokens.pos + 1;
+        let pixels = &data[start..start + count * bytes_per_sample];
+        for sample in pixels.chunks_exact(bytes_per_sample) {
+            let v = sample.iter().fold(0, |v, &b| v << 8 | usize::from(b));
+            samples.push(v.min(max) as f32 / max as f32);
+        }
+    } else {
+        for _ in 0..count {
+            let v: usize = tokens
+                .next()
+                .parse()
+                .map_err(|_| invalid("pixel data is truncated"))?;
+            samples.push(v.min(max) as f32 / max as f32);
+        }
+    }
+
+    Ok(Heightmap {
+        width,
+        height,
+        samples,
+    })
+}
+
+/// Reads a PNG. Colour images use their luminance.
+pub fn read_png(path: &Path) -> Result<Heightmap, FormatError> {
+    let invalid = |e: png::DecodingError| FormatError::Invalid(format!("png: {e}"));
+
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // palettes become RGB, low bit depths 8 bit
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(invalid)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(invalid)?;
+
+    let channels = info.color_type.samples();
+    let (max, bytes_per_sample) = match info.bit_depth {
+        png::BitDepth::Sixteen => (f32::from(u16::MAX), 2),
+        _ => (f32::from(u8::MAX), 1),
+    };
+    let value = |s: &[u8]| s.iter().fold(0, |v, &b| v << 8 | u32::from(b)) as f32 / max;
+    let samples = buf[..info.buffer_size()]
+        .chunks_exact(channels * bytes_per_sample)
+        .map(|pixel| {
+            let mut c = pixel.chunks_exact(bytes_per_sample).map(value);
+            match info.color_type {
+                png::ColorType::Rgb | png::ColorType::Rgba => {
+                    let (r, g, b) = (c.next().unwrap(), c.next().unwrap(), c.next().unwrap());
+                    0.2126 * r + 0.7152 * g + 0.0722 * b
+                }
+                _ => c.next().unwrap(),
+            }
+        })
+        .collect();
+
+    Ok(Heightmap {
+        width: info.width as usize,
+        height: info.height as usize,
+        samples,
+    })
+}
+
+/// Minimum and maximum column height over absolutely aligned squares of each
+/// size, level `k` covering `2^k` by `2^k` cells. Squares only partially
+/// covered by the heightmap have a minimum of 0.
+struct HeightPyramid {
+    levels: Vec<PyramidLevel>,
+}
+
+struct PyramidLevel {
+    /// absolute x and z index of the first square
+    first: [u32; 2],
+    /// number of squares along x and z
+    len: [usize; 2],
+    /// row major minimum and maximum height
+    ranges: Vec<(u32, u32)>,
+}
+
+impl PyramidLevel {
+    fn get(&self, x: u32, z: u32) -> (u32, u32) {
+        let (Some(i), Some(j)) = (x.checked_sub(self.first[0]), z.checked_sub(self.first[1]))
+        else {
+            return (0, 0);
+        };
+        let (i, j) = (i as usize, j as usize);
+        if i >= self.len[0] || j >= self.len[1] {
+            return (0, 0);
+        }
+        self.ranges[j * self.len[0] + i]
+    }
+}
+
+impl HeightPyramid {
+    /// `heights` holds `size[0]` by `size[1]` columns, the first one at
+    /// absolute cell `base`.
+    fn new(heights: Vec<u32>, size: [usize; 2], base: [u32; 2]) -> Self {
+        let mut levels = vec![PyramidLevel {
+            first: base,
+            len: size,
+            ranges: heights.iter().map(|&h| (h, h)).collect(),
+        }];
+        while levels.last().unwrap().len != [1, 1] {
+            let prev = levels.last().unwrap();
+            let first = prev.first.map(|f| f >> 1);
+            let last = [0, 1].map(|a| (prev.first[a] + prev.len[a] as u32 - 1) >> 1);
+            let len = [0, 1].map(|a| (last[a] - first[a]) as usize + 1);
+            let mut ranges = Vec::with_capacity(len[0] * len[1]);
+            for j in 0..len[1] as u32 {
+                for i in 0..len[0] as u32 {
+                    let (x, z) = (2 * (first[0] + i), 2 * (first[1] + j));
+                    let children =
+                        [(x, z), (x + 1, z), (x, z + 1), (x + 1, z + 1)].map(|(x, z)| {
+                            let inside = x >= prev.first[0]
+                                && z >= prev.first[1]
+                                && ((x - prev.first[0]) as usize) < prev.len[0]
+                                && ((z - prev.first[1]) as usize) < prev.len[1];
+                            inside.then(|| prev.get(x, z))
+                        });
+                    let min = children.iter().map(|c| c.map_or(0, |c| c.0)).min().unwrap();
+                    let max = children.iter().flatten().map(|c| c.1).max().unwrap();
+                    ranges.push((min, max));
+                }
+            }
+            levels.push(PyramidLevel { first, len, ranges });
+        }
+        Self { levels }
+    }
+
+    fn get(&self, level: u32, x: u32, z: u32) -> (u32, u32) {
+        match self.levels.get(level as usize) {
+            Some(l) => l.get(x, z),
+            // beyond the top only the square containing every column is not
+            // empty
+            None => {
+                let top = self.levels.last().unwrap();
+                let shift = level as usize + 1 - self.levels.len();
+                if [x, z] == top.first.map(|f| f >> shift) {
+                    top.ranges[0]
+                } else {
+                    (0, 0)
+                }
+            }
+        }
+    }
+}
+
+impl Heightmap {
+    /// Writes the terrain into the world and returns the number of leaves
+    /// inserted.
+    pub fn insert(
+        &self,
+        world: &mut VoxelBuffer,
+        options: &ImportOptions,
+    ) -> Result<u64, FormatError> {
+        let placement = options.placement;
+        let size = [self.width, self.height].map(|s| {
+            (s as f64 * options.pixel_size)
+                .round()
+                .max(1.0)
+                .min(f64::from(u32::MAX)) as u32
+        });
+        let too_large = || FormatError::Invalid("heightmap does not fit into the world".into());
+        // columns are at least one cell high, their full height is checked
+        // once it is known
+        placement
+            .cell_pos(Vector3::new(size[0] - 1, 0, size[1] - 1))
+            .ok_or_else(too_large)?;
+        if u64::from(size[0]) * u64::from(size[1]) > MAX_GRID_CELLS {
+            return Err(FormatError::Unsupported(format!(
+                "{}x{} columns are too many",
+                size[0], size[1]
+            )));
+        }
+        let size = size.map(|s| s as usize);
+
+        let mut heights = Vec::with_capacity(size[0] * size[1]);
+        for z in 0..size[1] {
+            for x in 0..size[0] {
+                // cell centres mapped onto pixel centres
+                let px = (x as f64 + 0.5) / options.pixel_size - 0.5;
+                let py = (z as f64 + 0.5) / options.pixel_size - 0.5;
+                let h = f64::from(self.sample(px, py)) * f64::from(options.max_height);
+                heights.push(h.round() as u32);
+            }
+        }
+        let max_height = heights.iter().copied().max().unwrap_or(0);
+
+        let extent = Vector3::new(size[0] as u32, max_height.max(1), size[1] as u32);
+        let shift = 31 - placement.level;
+        let base = placement.origin.map(|c| c >> shift);
+        placement
+            .cell_pos(extent.map(|e| e - 1))
+            .ok_or_else(too_large)?;
+
+        let pyramid = HeightPyramid::new(heights, size, [base.x, base.z]);
+        // the smallest aligned cube containing the whole terrain
+        let mut top = 0;
+        while (0..3).any(|a| base[a] >> top != (base[a] + extent[a] - 1) >> top) {
+            top += 1;
+        }
+
+        let mut fill = HeightmapFill {
+            world,
+            pyramid: &pyramid,
+            base_y: base.y,
+            level: placement.level,
+            material: options.material,
+            leaves: 0,
+        };
+        fill.cube(top, base.map(|c| c >> top))?;
+        log::info!(
+            "imported {}x{} heightmap as {}x{} columns, {} leaves",
+            self.width,
+            self.height,
+            size[0],
+            size[1],
+            fill.leaves
+        );
+        Ok(fill.leaves)
+    }
+}
+
+struct HeightmapFill<'a> {
+    world: &'a mut VoxelBuffer,
+    pyramid: &'a HeightPyramid,
+    /// absolute cell the columns start at
+    base_y: u32,
+    level: u32,
+    material: u32,
+    leaves: u64,
+}
+
+impl HeightmapFill<'_> {
+    /// Fills the cube of `2^k` cells with absolute index `idx` at that size.
+    fn cube(&mut self, k: u32, idx: Vector3<u32>) -> Result<(), VoxelError> {
+        let (min, max) = self.pyramid.get(k, idx.x, idx.z);
+        let y0 = u64::from(idx.y) << k;
+        let y1 = y0 + (1 << k);
+        let base = u64::from(self.base_y);
+
+        if y1 <= base || y0 >= base + u64::from(max) {
+            return Ok(());
+        }
+        // the cube of the whole octree is no leaf, it is filled through the
+        // eight root children
+        if k <= self.level && y0 >= base && y1 <= base + u64::from(min) {
+            let pos = idx.map(|c| ((u64::from(c) << k) << (31 - self.level)) as u32);
+            self.world
+                .add_voxel_with(pos, self.level - k, self.material, Overwrite::Replace)?;
+            self.leaves += 1;
+            return Ok(());
+        }
+        // a single cell is always either full or empty
+        debug_assert_ne!(k, 0);
+        for octant in 0..8 {
+            let child = Vector3::new(octant & 1, (octant >> 1) & 1, (octant >> 2) & 1);
+            self.cube(k - 1, idx * 2 + child)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::parse_pgm;
+    use crate::program::{
+        formats::{ImportOptions, Placement},
+        voxelbuffer::VoxelBuffer,
+    };
+
+    fn options(level: u32, max_height: u32) -> ImportOptions {
+        ImportOptions {
+            placement: Placement {
+                origin: Vector3::zeros(),
+                level,
+            },
+            material: 2,
+            pixel_size: 1.0,
+            max_height,
+            scale: 1.0,
+            solid: false,
+            volume_size: None,
+            threshold: 0.5,
+            bands: 0,
+        }
+    }
+
+    #[test]
+    fn columns_follow_the_pixels() {
+        let heightmap = parse_pgm(b"P2\n3 2\n4\n0 2 4\n1 3 4\n").unwrap();
+        let mut world = VoxelBuffer::empty();
+        let options = options(4, 4);
+        heightmap.insert(&mut world, &options).unwrap();
+
+        let side = options.placement.cell_side();
+        for (i, height) in [0, 2, 4, 1, 3, 4].into_iter().enumerate() {
+            let (x, z) = (i as u32 % 3, i as u32 / 3);
+            let column = world.count_in_box(
+                Vector3::new(x, 0, z) * side,
+                Vector3::new(x + 1, 16, z + 1) * side - Vector3::repeat(1),
+            );
+            assert_eq!(
+                column,
+                u128::from(height) * u128::from(side).pow(3),
+                "column {x} {z}"
+            );
+            assert!(world.get(Vector3::new(x, height, z) * side).is_none());
+            if height > 0 {
+                let top = world.get(Vector3::new(x, height - 1, z) * side).unwrap();
+                assert_eq!(top.material, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn terrain_filling_the_whole_octree() {
+        let heightmap = parse_pgm(
+            b"P2 4 4 255\n255 255 255 255\n255 255 255 255\n255 255 255 255\n255 255 255 255\n",
+        )
+        .unwrap();
+        let mut world = VoxelBuffer::empty();
+        let leaves = heightmap.insert(&mut world, &options(1, 4)).unwrap();
+        assert_eq!(leaves, 8);
+        assert_eq!(
+            world.count_in_box(Vector3::zeros(), Vector3::repeat(u32::MAX)),
+            1 << 96
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! Wavefront OBJ meshes, voxelized into every cell a triangle touches.
+//!
+//! Only vertex positions and faces are read, polygons are split into
+//! triangle fans. The mesh is scaled by `ImportOptions::scale` cells per
+//! model unit and its bounding box moved to the placement origin.
+//!
+//! OBJ coordinates are right handed with y up, the world's are left handed.
+//! The z axis is flipped on import so that models are not mirrored.
+//!
+//! With `ImportOptions::solid` the inside is filled as well: every cell that
+//! cannot be reached from outside the bounding box without crossing the
+//! surface. Meshes with holes leak and stay hollow.
+
+use std::path::Path;
+
+use nalgebra::Vector3;
+
+use super::{cells::BitGrid, FormatError, ImportOptions};
+use crate::program::voxelbuffer::VoxelBuffer;
+
+pub struct Mesh {
+    vertices: Vec<Vector3<f64>>,
+    triangles: Vec<[usize; 3]>,
+}
+
+pub fn read(path: &Path) -> Result<Mesh, FormatError> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+pub fn parse(text: &str) -> Result<Mesh, FormatError> {
+    let mut mesh = Mesh {
+        vertices: Vec::new(),
+        triangles: Vec::new(),
+    };
+    for (line_idx, line) in text.lines().enumerate() {
+        let invalid =
+            |reason: &str| FormatError::Invalid(format!("obj line {}: {reason}", line_idx + 1));
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("v") => {
+                let mut coord = || {
+                    fields
+                        .next()
+                        .and_then(|c| c.parse::<f64>().ok())
+                        .filter(|c| c.is_finite())
+                        .ok_or_else(|| invalid("bad vertex"))
+                };
+                let (x, y, z) = (coord()?, coord()?, coord()?);
+                mesh.vertices.push(Vector3::new(x, y, -z));
+            }
+            Some("f") => {
+                // v, v/vt, v//vn or v/vt/vn, negative indices count from the end
+                let corners = fields
+                    .map(|corner| {
+                        let index: i64 = corner
+                            .split('/')
+                            .next()
+                            .and_then(|i| i.parse().ok())
+                            .ok_or_else(|| invalid("bad face index"))?;
+                        let len = mesh.vertices.len() as i64;
+                        let index = if index < 0 { len + index } else { index - 1 };
+                        if !(0..len).contains(&index) {
+                            return Err(invalid("face index out of range"));
+                        }
+                        Ok(index as usize)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                if corners.len() < 3 {
+                    return Err(invalid("face with less than three corners"));
+                }
+                for i in 1..corners.len() - 1 {
+                    mesh.triangles
+                        .push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(mesh)
+}
+
+impl Mesh {
+    /// Voxelizes the mesh into the world and returns the number of leaves
+    /// inserted.
+    pub fn insert(
+        &self,
+        world: &mut VoxelBuffer,
+        options: &ImportOptions,
+    ) -> Result<u64, FormatError> {
+        let Some(min) = self.vertices.iter().copied().reduce(|a, b| a.inf(&b)) else {
+            return Ok(0);
+        };
+        let max = self
+            .vertices
+            .iter()
+            .copied()
+            .reduce(|a, b| a.sup(&b))
+            .unwrap();
+        // nudged off the cell borders, so that axis aligned faces mark a
+        // single layer of cells instead of the two they touch
+        let cells = |v: Vector3<f64>| ((v - min) * options.scale).add_scalar(1e-6);
+
+        let extent = cells(max).map(|c| c.floor() + 1.0);
+        if !extent.iter().all(|&e| e <= f64::from(u32::MAX)) {
+            return Err(FormatError::Unsupported(format!(
+                "mesh of {:.0}x{:.0}x{:.0} cells is too large, use a smaller scale",
+                extent.x, extent.y, extent.z
+            )));
+        }
+        let size = extent.map(|e| e as u32);
+        let mut surface = BitGrid::new(size)?;
+        for tri in &self.triangles {
+            let tri = tri.map(|i| cells(self.vertices[i]));
+            let lo = tri[0].inf(&tri[1]).inf(&tri[2]).map(|c| c.floor() as u32);
+            let hi = tri[0]
+                .sup(&tri[1])
+                .sup(&tri[2])
+                .zip_map(&size, |c, s| (c.floor() as u32).min(s - 1));
+            for z in lo.z..=hi.z {
+                for y in lo.y..=hi.y {
+                    for x in lo.x..=hi.x {
+                        let cell = Vector3::new(x, y, z);
+                        if triangle_overlaps_cell(&tri, cell.cast()) {
+                            surface.set(cell);
+                        }
+                    }
+                }
+            }
+        }
+
+        let outside = if options.solid {
+            Some(flood_outside(&surface)?)
+        } else {
+            None
+        };
+        let material = options.material;
+        let leaves = super::cells::insert_cells(world, options.placement, size, |p| {
+            let solid = surface.get(p) || outside.as_ref().is_some_and(|o| !o.get(p.add_scalar(1)));
+            solid.then_some(material)
+        })?;
+        log::info!(
+            "imported mesh with {} triangles into {}x{}x{} cells, {} leaves",
+            self.triangles.len(),
+            size.x,
+            size.y,
+            size.z,
+            leaves
+        );
+        Ok(leaves)
+    }
+}
+
+/// Separating axis test of a triangle against the unit cell with minimum
+/// corner `cell`. Touching counts as overlapping.
+fn triangle_overlaps_cell(tri: &[Vector3<f64>; 3], cell: Vector3<f64>) -> bool {
+    let half = 0.5;
+    let centre = cell.add_scalar(half);
+    let v = tri.map(|p| p - centre);
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    let separated = |axis: Vector3<f64>| {
+        let p = v.map(|v| v.dot(&axis));
+        let r = half * axis.abs().sum();
+        p.iter().copied().fold(f64::INFINITY, f64::min) > r
+            || p.iter().copied().fold(f64::NEG_INFINITY, f64::max) < -r
+    };
+
+    // box faces, triangle plane, then the nine edge cross products
+    let box_axes = [Vector3::x(), Vector3::y(), Vector3::z()];
+    if box_axes.iter().any(|&a| separated(a)) {
+        return false;
+    }
+    if separated(edges[0].cross(&edges[1])) {
+        return false;
+    }
+    !box_axes
+        .iter()
+        .any(|a| edges.iter().any(|e| separated(a.cross(e))))
+}
+
+/// Marks every cell reachable from outside without entering `surface`. The
+/// result has a one cell border around the grid, so cell `p` of the grid is
+/// at `p + 1`.
+fn flood_outside(surface: &BitGrid) -> Result<BitGrid, FormatError> {
+    let size = surface.size();
+    let padded = size.add_scalar(2);
+    let mut outside = BitGrid::new(padded)?;
+    let blocked = |p: Vector3<u32>| {
+        let inside = (0..3).all(|a| p[a] >= 1 && p[a] <= size[a]);
+        inside && surface.get(p.map(|c| c - 1))
+    };
+
+    let mut stack = vec![Vector3::zeros()];
+    outside.set(Vector3::zeros());
+    while let Some(p) = stack.pop() {
+        for axis in 0..3 {
+            for step in [-1_i64, 1] {
+                let c = i64::from(p[axis]) + step;
+                if c < 0 || c >= i64::from(padded[axis]) {
+                    continue;
+                }
+                let mut n = p;
+                n[axis] = c as u32;
+                if !outside.get(n) && !blocked(n) {
+                    outside.set(n);
+                    stack.push(n);
+                }
+            }
+        }
+    }
+    Ok(outside)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::parse;
+    use crate::program::{
+        formats::{FormatError, ImportOptions, Placement},
+        voxelbuffer::VoxelBuffer,
+    };
+
+    fn options(scale: f64) -> ImportOptions {
+        ImportOptions {
+            placement: Placement {
+                origin: Vector3::zeros(),
+                level: 3,
+            },
+            material: 2,
+            pixel_size: 1.0,
+            max_height: 1,
+            scale,
+            solid: false,
+            volume_size: None,
+            threshold: 0.5,
+            bands: 0,
+        }
+    }
+
+    #[test]
+    fn z_is_flipped() {
+        // a small triangle at the origin and one further along x and z
+        let mesh = parse(
+            "v 0 0 0\nv 0.5 0 0\nv 0 0.5 0\n\
+             v 3 0 3\nv 3.5 0 3\nv 3 0.5 3\n\
+             f 1 2 3\nf 4 5 6\n",
+        )
+        .unwrap();
+        let mut world = VoxelBuffer::empty();
+        let options = options(1.0);
+        mesh.insert(&mut world, &options).unwrap();
+
+        let side = options.placement.cell_side();
+        let cell = |x, z| world.get(Vector3::new(x, 0, z) * side);
+        assert!(cell(0, 3).is_some());
+        assert!(cell(3, 0).is_some());
+        assert!(cell(0, 0).is_none());
+        assert!(cell(3, 3).is_none());
+    }
+
+    #[test]
+    fn huge_scale_is_refused() {
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let result = mesh.insert(&mut VoxelBuffer::empty(), &options(1e12));
+        assert!(matches!(result, Err(FormatError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,437 @@
+//! Point clouds from PLY (ASCII and binary) and plain XYZ files.
+//!
+//! Every point fills the cell it falls into. The cloud is scaled by
+//! `ImportOptions::scale` cells per unit and its bounding box moved to the
+//! placement origin. Points are sorted by Morton code and the octree is
+//! built bottom-up in one pass instead of inserting them one by one.
+//!
+//! Per-point colours become materials, rounded to 5 bits per channel so
+//! that noisy scans do not fill the palette. Clouds without colours use
+//! `ImportOptions::material`.
+
+use std::{collections::HashMap, path::Path};
+
+use nalgebra::Vector3;
+
+use super::{srgb_material, FormatError, ImportOptions};
+use crate::program::{
+    palette::Palette,
+    voxelbuffer::{morton_code, VoxelBuffer, MAX_BULK_DEPTH},
+};
+
+pub struct PointCloud {
+    positions: Vec<Vector3<f64>>,
+    /// sRGB colour of each point, empty if the file has none
+    colors: Vec<[u8; 3]>,
+}
+
+pub fn read_ply(path: &Path) -> Result<PointCloud, FormatError> {
+    parse_ply(&std::fs::read(path)?)
+}
+
+pub fn read_xyz(path: &Path) -> Result<PointCloud, FormatError> {
+    parse_xyz(&std::fs::read_to_string(path)?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    /// Decodes a little endian value of this type.
+    fn decode(self, b: [u8; 8]) -> f64 {
+        match self {
+            Scalar::I8 => f64::from(b[0] as i8),
+            Scalar::U8 => f64::from(b[0]),
+            Scalar::I16 => f64::from(i16::from_le_bytes([b[0], b[1]])),
+            Scalar::U16 => f64::from(u16::from_le_bytes([b[0], b[1]])),
+            Scalar::I32 => f64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            Scalar::U32 => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            Scalar::F32 => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            Scalar::F64 => f64::from_le_bytes(b),
+        }
+    }
+
+    /// Converts a colour channel of this type to 8 bits. Floating point
+    /// channels range from 0 to 1, integer ones over their type.
+    fn channel(self, v: f64) -> u8 {
+        let v = match self {
+            Scalar::F32 | Scalar::F64 => v * 255.0,
+            Scalar::I16 | Scalar::U16 => v / 257.0,
+            _ => v,
+        };
+        v.round().clamp(0.0, 255.0) as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Property {
+    Scalar(Scalar),
+    List { count: Scalar, item: Scalar },
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+/// Values of the PLY body in file order.
+struct Values<'a> {
+    data: &'a [u8],
+    pos: usize,
+    encoding: Encoding,
+}
+
+impl Values<'_> {
+    fn next(&mut self, scalar: Scalar) -> Result<f64, FormatError> {
+        let truncated = || FormatError::Invalid("ply: vertex data is truncated".into());
+        if self.encoding == Encoding::Ascii {
+            while self
+                .data
+                .get(self.pos)
+                .is_some_and(|b| b.is_ascii_whitespace())
+            {
+                self.pos += 1;
+            }
+            let start = self.pos;
+            while self
+                .data
+                .get(self.pos)
+                .is_some_and(|b| !b.is_ascii_whitespace())
+            {
+                self.pos += 1;
+            }
+            if start == self.pos {
+                return Err(truncated());
+            }
+            return std::str::from_utf8(&self.data[start..self.pos])
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| FormatError::Invalid("ply: bad number".into()));
+        }
+
+        let size = scalar.size();
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + size)
+            .ok_or_else(truncated)?;
+        self.pos += size;
+        let mut le = [0; 8];
+        le[..size].copy_from_slice(bytes);
+        if self.encoding == Encoding::BigEndian {
+            le[..size].reverse();
+        }
+        Ok(scalar.decode(le))
+    }
+}
+
+pub fn parse_ply(data: &[u8]) -> Result<PointCloud, FormatError> {
+    let invalid = |reason: &str| FormatError::Invalid(format!("ply: {reason}"));
+
+    let mut pos = 0;
+    let mut line = || {
+        let rest = data.get(pos..).filter(|r| !r.is_empty())?;
+        let len = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(rest.len(), |l| l + 1);
+        pos += len;
+        Some(std::str::from_utf8(&rest[..len]).unwrap_or_default().trim())
+    };
+    if line() != Some("ply") {
+        return Err(invalid("not a ply file"));
+    }
+
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    loop {
+        let line = line().ok_or_else(|| invalid("header is truncated"))?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["end_header"] => break,
+            ["format", format, _version] => {
+                encoding = Some(match *format {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::LittleEndian,
+                    "binary_big_endian" => Encoding::BigEndian,
+                    _ => return Err(invalid("unknown format")),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| invalid("bad element count"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, item, name] => {
+                let property = Property::List {
+                    count: Scalar::parse(count).ok_or_else(|| invalid("unknown type"))?,
+                    item: Scalar::parse(item).ok_or_else(|| invalid("unknown type"))?,
+                };
+                elements
+                    .last_mut()
+                    .ok_or_else(|| invalid("property outside of an element"))?
+                    .properties
+                    .push((name.to_string(), property));
+            }
+            ["property", scalar, name] => {
+                let property =
+                    Property::Scalar(Scalar::parse(scalar).ok_or_else(|| invalid("unknown type"))?);
+                elements
+                    .last_mut()
+                    .ok_or_else(|| invalid("property outside of an element"))?
+                    .properties
+                    .push((name.to_string(), property));
+            }
+            _ => {}
+        }
+    }
+    let encoding = encoding.ok_or_else(|| invalid("missing format"))?;
+
+    let mut values = Values {
+        data,
+        pos,
+        encoding,
+    };
+    for element in &elements {
+        let find = |name: &str| {
+            element
+                .properties
+                .iter()
+                .position(|(n, p)| n == name && matches!(p, Property::Scalar(_)))
+        };
+        let is_vertex = element.name == "vertex";
+        let coords = [find("x"), find("y"), find("z")];
+        let channels = [
+            find("red").or_else(|| find("diffuse_red")),
+            find("green").or_else(|| find("diffuse_green")),
+            find("blue").or_else(|| find("diffuse_blue")),
+        ];
+        if is_vertex && coords.contains(&None) {
+            return Err(invalid("vertices without x, y and z"));
+        }
+        let has_color = !channels.contains(&None);
+
+        let mut cloud = PointCloud {
+            positions: Vec::new(),
+            colors: Vec::new(),
+        };
+        let mut row = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            for (value, (_, property)) in row.iter_mut().zip(&element.properties) {
+                match *property {
+                    Property::Scalar(scalar) => *value = values.next(scalar)?,
+                    Property::List { count, item } => {
+                        let count = values.next(count)?;
+                        if !(0.0..=f64::from(u32::MAX)).contains(&count) {
+                            return Err(invalid("bad list length"));
+                        }
+                        for _ in 0..count as u32 {
+                            values.next(item)?;
+                        }
+                    }
+                }
+            }
+            if !is_vertex {
+                continue;
+            }
+            let point = Vector3::from(coords.map(|c| row[c.unwrap()]));
+            if !point.iter().all(|c| c.is_finite()) {
+                return Err(invalid("bad vertex"));
+            }
+            cloud.positions.push(point);
+            if has_color {
+                cloud.colors.push(channels.map(|c| {
+                    let c = c.unwrap();
+                    let Property::Scalar(scalar) = element.properties[c].1 else {
+                        unreachable!("colour channels are scalar properties")
+                    };
+                    scalar.channel(row[c])
+                }));
+            }
+        }
+        if is_vertex {
+            return Ok(cloud);
+        }
+    }
+    Err(invalid("no vertex element"))
+}
+
+/// Parses lines of `x y z`, optionally followed by an `r g b` colour from 0
+/// to 255 or by an intensity and the colour. The first point decides which
+/// columns hold the colour.
+pub fn parse_xyz(text: &str) -> Result<PointCloud, FormatError> {
+    let mut cloud = PointCloud {
+        positions: Vec::new(),
+        colors: Vec::new(),
+    };
+    let mut color_columns = None;
+    for (line_idx, line) in text.lines().enumerate() {
+        let invalid =
+            |reason: &str| FormatError::Invalid(format!("xyz line {}: {reason}", line_idx + 1));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        let fields = line
+            .split(|c: char| c.is_ascii_whitespace() || c == ',')
+            .filter(|f| !f.is_empty())
+            .map(|f| f.parse::<f64>().ok().filter(|f| f.is_finite()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("bad number"))?;
+        if fields.len() < 3 {
+            return Err(invalid("point with less than three coordinates"));
+        }
+
+        let columns = *color_columns.get_or_insert(match fields.len() {
+            6 => Some(3),
+            7 => Some(4),
+            _ => None,
+        });
+        cloud
+            .positions
+            .push(Vector3::new(fields[0], fields[1], fields[2]));
+        if let Some(first) = columns {
+            let rgb = fields
+                .get(first..first + 3)
+                .ok_or_else(|| invalid("point without a colour"))?;
+            cloud
+                .colors
+                .push([0, 1, 2].map(|i| rgb[i].round().clamp(0.0, 255.0) as u8));
+        }
+    }
+    Ok(cloud)
+}
+
+impl PointCloud {
+    /// Writes the cells containing a point into the world and returns the
+    /// number of cells filled.
+    pub fn insert(
+        &self,
+        world: &mut VoxelBuffer,
+        palette: &mut Palette,
+        options: &ImportOptions,
+    ) -> Result<u64, FormatError> {
+        let Some(min) = self.positions.iter().copied().reduce(|a, b| a.inf(&b)) else {
+            return Ok(0);
+        };
+        let max = self
+            .positions
+            .iter()
+            .copied()
+            .reduce(|a, b| a.sup(&b))
+            .unwrap();
+        let cell = |p: Vector3<f64>| ((p - min) * options.scale).map(|c| c.floor() as u64);
+
+        // absolute cell coordinates at the placement level
+        let level = options.placement.level;
+        let shift = 31 - level;
+        let lo = options.placement.origin.map(|c| u64::from(c >> shift));
+        let hi = lo.zip_map(&cell(max), u64::saturating_add);
+        if hi.iter().any(|&c| c >= 1 << (level + 1)) {
+            return Err(FormatError::Invalid(
+                "point cloud does not fit into the world".into(),
+            ));
+        }
+
+        // smallest aligned cube containing every cell
+        let mut depth = 0;
+        while (0..3).any(|a| lo[a] >> depth != hi[a] >> depth) {
+            depth += 1;
+        }
+        if depth > MAX_BULK_DEPTH {
+            return Err(FormatError::Unsupported(format!(
+                "point cloud spans more than 2^{MAX_BULK_DEPTH} cells, use a coarser level or scale"
+            )));
+        }
+        let cube = lo.map(|c| c >> depth << depth);
+
+        let mut materials: HashMap<[u8; 3], u32> = HashMap::new();
+        let mut leaves = Vec::with_capacity(self.positions.len());
+        for (i, &p) in self.positions.iter().enumerate() {
+            let material = match self.colors.get(i) {
+                Some(rgb) => {
+                    // middle of the 5 bit bucket
+                    let rgb = rgb.map(|c| c & !0b111 | 0b100);
+                    *materials.entry(rgb).or_insert_with(|| {
+                        let id = palette.material_count();
+                        palette.set(id, srgb_material(rgb));
+                        id
+                    })
+                }
+                None => options.material,
+            };
+            let rel = (lo + cell(p) - cube).map(|c| c as u32);
+            leaves.push((morton_code(rel), material));
+        }
+        // stable, so the first point of a cell decides its material
+        leaves.sort_by_key(|&(code, _)| code);
+        leaves.dedup_by_key(|&mut (code, _)| code);
+
+        let count = if depth <= level {
+            let pos = cube.map(|c| (c << shift) as u32);
+            world.insert_sorted(pos, level - depth, depth, leaves)?
+        } else {
+            // the cube is the whole octree, which has no parent to insert
+            // into, so each root child is built on its own
+            let bits = 3 * level;
+            let mut count = 0;
+            for octant in 0..8 {
+                let start = leaves.partition_point(|&(code, _)| code >> bits < octant);
+                let end = leaves.partition_point(|&(code, _)| code >> bits <= octant);
+                let pos = Vector3::new(octant & 1, octant >> 1 & 1, octant >> 2 & 1)
+                    .map(|b| (b as u32) << 31);
+                let group = leaves[start..end]
+                    .iter()
+                    .map(|&(code, material)| (code & ((1 << bits) - 1), material));
+                count += world.insert_sorted(pos, 0, level, group)?;
+            }
+            count
+        };
+        log::info!(
+            "imported point cloud with {} points into {} cells, {} new materials",
+            self.positions.len(),
+            count,
+            materials.len()
+        );
+        Ok(count)
+    }
+}
@@ -0,0 +1,458 @@
+//! Surface meshes of a world region, written as Wavefront OBJ or binary STL.
+//!
+//! The region is looked at in cells of the placement level, like the vox
+//! export. Only faces between a solid cell and an empty one are emitted,
+//! the outside of the region counts as empty so the mesh is closed.
+//! Coplanar faces of the same material are greedily merged into rectangles.
+//!
+//! Merged rectangles meet the corners of smaller neighbours in the middle
+//! of their edges. Those corners are added to the rectangle's outline, so
+//! every edge is shared by matching faces and the mesh has no cracks where
+//! differently sized parts meet.
+//!
+//! One mesh unit is one cell. The world is left handed and both formats are
+//! right handed: OBJ files keep the world's y axis up with z flipped, STL
+//! files swap y and z to have z up as slicers expect. Mirroring reverses the
+//! outlines, so they are written backwards. OBJ files get a group and a
+//! material per world material.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use nalgebra::Vector3;
+
+use super::{material_srgb, FormatError, Placement};
+use crate::program::{palette::Palette, voxelbuffer::VoxelBuffer};
+
+/// Largest cross section of an exported region, in cells.
+const MAX_SLICE_CELLS: u64 = 1 << 26;
+
+struct Face {
+    /// axis of the normal
+    axis: usize,
+    /// whether the normal points along the positive axis
+    positive: bool,
+    material: u32,
+    /// outline counter-clockwise seen from outside, in cells relative to the
+    /// region
+    corners: Vec<Vector3<u32>>,
+}
+
+pub struct Surface {
+    size: Vector3<u32>,
+    /// sorted by material
+    faces: Vec<Face>,
+}
+
+impl Surface {
+    /// Extracts the surface of the box of `size` cells at `placement`.
+    pub fn extract(
+        world: &VoxelBuffer,
+        placement: Placement,
+        size: Vector3<u32>,
+    ) -> Result<Self, FormatError> {
+        let too_large = || FormatError::Invalid("export region does not fit into the world".into());
+        if size.iter().any(|&s| s == 0) {
+            return Err(FormatError::Invalid("export region is empty".into()));
+        }
+        placement
+            .cell_pos(size.map(|s| s - 1))
+            .ok_or_else(too_large)?;
+        let shift = 31 - placement.level;
+
+        let mut faces = Vec::new();
+        for axis in 0..3 {
+            // u and v span the slice, u x v points along the axis
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            if u64::from(size[u]) * u64::from(size[v]) > MAX_SLICE_CELLS {
+                return Err(FormatError::Unsupported(format!(
+                    "{}x{}x{} cells have too large a cross section",
+                    size.x, size.y, size.z
+                )));
+            }
+            let width = size[u] as usize;
+
+            // material plus one of every cell in the slice, 0 if empty
+            let slice = |d: u32| {
+                let mut cells = vec![0; width * size[v] as usize];
+                let mut lo = Vector3::zeros();
+                lo[axis] = d;
+                let mut hi = size.map(|s| s - 1);
+                hi[axis] = d;
+                let min = placement.cell_pos(lo).unwrap();
+                let max = placement
+                    .cell_pos(hi)
+                    .unwrap()
+                    .add_scalar(placement.cell_side() - 1);
+                world.for_each_leaf(min, max, |lo, hi, material| {
+                    let lo = (lo - min).map(|c| c >> shift);
+                    let hi = (hi - min).map(|c| c >> shift);
+                    for cv in lo[v]..=hi[v] {
+                        for cu in lo[u]..=hi[u] {
+                            let cell = &mut cells[cu as usize + width * cv as usize];
+                            if *cell == 0 {
+                                *cell = material + 1;
+                            }
+                        }
+                    }
+                });
+                cells
+            };
+
+            let mut below = vec![0; width * size[v] as usize];
+            for d in 0..=size[axis] {
+                let above = if d < size[axis] {
+                    slice(d)
+                } else {
+                    vec![0; below.len()]
+                };
+                for positive in [true, false] {
+                    // faces on the plane between the two slices
+                    let (solid, empty) = if positive {
+                        (&below, &above)
+                    } else {
+                        (&above, &below)
+                    };
+                    let mut mask: Vec<u32> = solid
+                        .iter()
+                        .zip(empty)
+                        .map(|(&s, &e)| if e == 0 { s } else { 0 })
+                        .collect();
+                    merge_rectangles(&mut mask, width, |u0, v0, u1, v1, material| {
+                        let corner = |cu: usize, cv: usize| {
+                            let mut p = Vector3::zeros();
+                            p[axis] = d;
+                            p[u] = cu as u32;
+                            p[v] = cv as u32;
+                            p
+                        };
+                        let mut corners = vec![
+                            corner(u0, v0),
+                            corner(u1, v0),
+                            corner(u1, v1),
+                            corner(u0, v1),
+                        ];
+                        if !positive {
+                            corners.reverse();
+                        }
+                        faces.push(Face {
+                            axis,
+                            positive,
+                            material: material - 1,
+                            corners,
+                        });
+                    });
+                }
+                below = above;
+            }
+        }
+
+        split_edges(&mut faces);
+        faces.sort_by_key(|f| f.material);
+        Ok(Surface { size, faces })
+    }
+
+    /// Writes an OBJ file with a group per material and the material colours
+    /// in an MTL file next to it.
+    pub fn write_obj(&self, path: &Path, palette: &Palette) -> Result<(), FormatError> {
+        let mtl_path = path.with_extension("mtl");
+        let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+        let mut materials: Vec<u32> = self.faces.iter().map(|f| f.material).collect();
+        materials.dedup();
+        for &material in &materials {
+            let [r, g, b, a] = material_srgb(&palette.get(material)).map(|c| f32::from(c) / 255.0);
+            writeln!(mtl, "newmtl material_{material}")?;
+            writeln!(mtl, "Kd {r} {g} {b}")?;
+            writeln!(mtl, "d {a}")?;
+        }
+        mtl.flush()?;
+
+        let mut out = BufWriter::new(File::create(path)?);
+        if let Some(name) = mtl_path.file_name() {
+            writeln!(out, "mtllib {}", name.to_string_lossy())?;
+        }
+        let mut vertices = HashMap::new();
+        for face in &self.faces {
+            for c in &face.corners {
+                let next = vertices.len() + 1;
+                if let Entry::Vacant(entry) = vertices.entry(*c) {
+                    writeln!(out, "v {} {} {}", c.x, c.y, self.size.z - c.z)?;
+                    entry.insert(next);
+                }
+            }
+        }
+        let mut current = None;
+        for face in &self.faces {
+            if current != Some(face.material) {
+                current = Some(face.material);
+                writeln!(out, "g material_{}", face.material)?;
+                writeln!(out, "usemtl material_{}", face.material)?;
+            }
+            write!(out, "f")?;
+            for c in face.corners.iter().rev() {
+                write!(out, " {}", vertices[c])?;
+            }
+            writeln!(out)?;
+        }
+        out.flush()?;
+
+        log::info!(
+            "exported surface with {} faces and {} vertices in {} materials",
+            self.faces.len(),
+            vertices.len(),
+            materials.len()
+        );
+        Ok(())
+    }
+
+    /// Writes a binary STL file. Faces with corners in the middle of their
+    /// edges are split into a fan around their centre.
+    pub fn write_stl(&self, path: &Path) -> Result<(), FormatError> {
+        let mut triangles = Vec::new();
+        for face in &self.faces {
+            let corners: Vec<Vector3<f32>> = face.corners.iter().map(|c| c.cast()).collect();
+            if corners.len() == 4 {
+                triangles.push((face, [corners[0], corners[1], corners[2]]));
+                triangles.push((face, [corners[0], corners[2], corners[3]]));
+            } else {
+                let centre = corners.iter().sum::<Vector3<f32>>() / corners.len() as f32;
+                for i in 0..corners.len() {
+                    triangles.push((face, [centre, corners[i], corners[(i + 1) % corners.len()]]));
+                }
+            }
+        }
+
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut header = [0; 80];
+        let title = b"voxelcraft surface";
+        header[..title.len()].copy_from_slice(title);
+        out.write_all(&header)?;
+        out.write_all(&(triangles.len() as u32).to_le_bytes())?;
+        for (face, triangle) in &triangles {
+            let mut normal = Vector3::zeros();
+            normal[face.axis] = if face.positive { 1.0 } else { -1.0 };
+            // y and z swapped for z up
+            let normal = [normal.x, normal.z, normal.y];
+            let corners = triangle.iter().rev().flat_map(|p| [p.x, p.z, p.y]);
+            for value in normal.into_iter().chain(corners) {
+                out.write_all(&value.to_le_bytes())?;
+            }
+            out.write_all(&[0; 2])?;
+        }
+        out.flush()?;
+
+        log::info!(
+            "exported surface with {} faces as {} triangles",
+            self.faces.len(),
+            triangles.len()
+        );
+        Ok(())
+    }
+}
+
+/// Greedily covers the non-zero cells of a row major mask with rectangles of
+/// equal values, clearing the mask. `emit` gets the corners of each
+/// rectangle, the upper ones exclusive, and its value.
+fn merge_rectangles(
+    mask: &mut [u32],
+    width: usize,
+    mut emit: impl FnMut(usize, usize, usize, usize, u32),
+) {
+    let height = mask.len() / width;
+    for v0 in 0..height {
+        let mut u0 = 0;
+        while u0 < width {
+            let value = mask[u0 + width * v0];
+            if value == 0 {
+                u0 += 1;
+                continue;
+            }
+            let mut u1 = u0 + 1;
+            while u1 < width && mask[u1 + width * v0] == value {
+                u1 += 1;
+            }
+            let mut v1 = v0 + 1;
+            while v1 < height
+                && mask[u0 + width * v1..u1 + width * v1]
+                    .iter()
+                    .all(|&m| m == value)
+            {
+                v1 += 1;
+            }
+            for v in v0..v1 {
+                mask[u0 + width * v..u1 + width * v].fill(0);
+            }
+            emit(u0, v0, u1, v1, value);
+            u0 = u1;
+        }
+    }
+}
+
+/// Adds the corners of all faces that lie inside the edges of another face
+/// to that face's outline.
+fn split_edges(faces: &mut [Face]) {
+    // coordinates of corners along each axis parallel line, keyed by the
+    // axis and the other two coordinates
+    let mut lines: HashMap<(usize, u32, u32), Vec<u32>> = HashMap::new();
+    for face in faces.iter() {
+        for c in &face.corners {
+            for axis in 0..3 {
+                let key = (axis, c[(axis + 1) % 3], c[(axis + 2) % 3]);
+                lines.entry(key).or_default().push(c[axis]);
+            }
+        }
+    }
+    for line in lines.values_mut() {
+        line.sort_unstable();
+        line.dedup();
+    }
+
+    for face in faces {
+        let mut corners = Vec::with_capacity(face.corners.len());
+        for (i, &from) in face.corners.iter().enumerate() {
+            let to = face.corners[(i + 1) % face.corners.len()];
+            corners.push(from);
+            let axis = (0..3).find(|&a| from[a] != to[a]).unwrap();
+            let line = &lines[&(axis, from[(axis + 1) % 3], from[(axis + 2) % 3])];
+            let (lo, hi) = (from[axis].min(to[axis]), from[axis].max(to[axis]));
+            let start = line.partition_point(|&c| c <= lo);
+            let end = line.partition_point(|&c| c < hi);
+            let inner = line[start..end].iter().map(|&c| {
+                let mut p = from;
+                p[axis] = c;
+                p
+            });
+            if from[axis] < to[axis] {
+                corners.extend(inner);
+            } else {
+                corners.extend(inner.rev());
+            }
+        }
+        face.corners = corners;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::Surface;
+    use crate::program::{
+        formats::Placement,
+        palette::Palette,
+        voxelbuffer::{Overwrite, VoxelBuffer},
+    };
+
+    const PLACEMENT: Placement = Placement {
+        origin: Vector3::new(0, 0, 0),
+        level: 3,
+    };
+
+    /// A world with the cells at `cells` solid, in cells of `PLACEMENT`.
+    fn world(cells: &[[u32; 3]]) -> VoxelBuffer {
+        let mut world = VoxelBuffer::empty();
+        for &cell in cells {
+            let pos = Vector3::from(cell) * PLACEMENT.cell_side();
+            world
+                .add_voxel_with(pos, PLACEMENT.level, 1, Overwrite::Replace)
+                .unwrap();
+        }
+        world
+    }
+
+    /// Volume enclosed by the triangles, positive if they are
+    /// counter-clockwise seen from outside in a right handed frame.
+    fn signed_volume(triangles: &[[Vector3<f64>; 3]]) -> f64 {
+        triangles
+            .iter()
+            .map(|[a, b, c]| a.dot(&b.cross(c)) / 6.0)
+            .sum()
+    }
+
+    fn temp_path(extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "voxelcraft-surface-{}.{extension}",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn obj_flips_z_and_faces_outwards() {
+        // an L of four cells, the longer arm along z
+        let world = world(&[[0, 0, 0], [0, 1, 0], [0, 0, 1], [0, 0, 2]]);
+        let surface = Surface::extract(&world, PLACEMENT, Vector3::new(1, 2, 3)).unwrap();
+        let path = temp_path("obj");
+        surface.write_obj(&path, &Palette::new()).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("mtl")).unwrap();
+
+        let mut vertices = Vec::new();
+        let mut triangles = Vec::new();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("v") => {
+                    let c: Vec<f64> = fields.map(|c| c.parse().unwrap()).collect();
+                    vertices.push(Vector3::new(c[0], c[1], c[2]));
+                }
+                Some("f") => {
+                    let corners: Vec<usize> = fields.map(|i| i.parse().unwrap()).collect();
+                    for i in 1..corners.len() - 1 {
+                        triangles.push(
+                            [corners[0], corners[i], corners[i + 1]].map(|i| vertices[i - 1]),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+        // the upper cell at world z 0 ends up at the far end
+        let min = vertices.iter().copied().reduce(|a, b| a.inf(&b)).unwrap();
+        let max = vertices.iter().copied().reduce(|a, b| a.sup(&b)).unwrap();
+        assert_eq!(
+            (min, max),
+            (Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 2.0, 3.0))
+        );
+        assert!(vertices.contains(&Vector3::new(0.0, 2.0, 3.0)));
+        assert!(!vertices.contains(&Vector3::new(0.0, 2.0, 0.0)));
+        assert!((signed_volume(&triangles) - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stl_swaps_y_and_z_and_faces_outwards() {
+        let world = world(&[[0, 0, 0], [0, 1, 0], [0, 0, 1], [0, 0, 2]]);
+        let surface = Surface::extract(&world, PLACEMENT, Vector3::new(1, 2, 3)).unwrap();
+        let path = temp_path("stl");
+        surface.write_stl(&path).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let count = u32::from_le_bytes(data[80..84].try_into().unwrap()) as usize;
+        assert_eq!(data.len(), 84 + 50 * count);
+        let mut triangles = Vec::new();
+        for record in data[84..].chunks_exact(50) {
+            let value = |i: usize| {
+                f64::from(f32::from_le_bytes(
+                    record[4 * i..4 * i + 4].try_into().unwrap(),
+                ))
+            };
+            let vector = |i: usize| Vector3::new(value(i), value(i + 1), value(i + 2));
+            let triangle = [vector(3), vector(6), vector(9)];
+            let normal = (triangle[1] - triangle[0])
+                .cross(&(triangle[2] - triangle[0]))
+                .normalize();
+            assert!((normal - vector(0)).norm() < 1e-6);
+            triangles.push(triangle);
+        }
+        // the shorter arm along world y stands along z
+        let vertices = triangles.iter().flatten();
+        let max = vertices.copied().reduce(|a, b| a.sup(&b)).unwrap();
+        assert_eq!(max, Vector3::new(1.0, 3.0, 2.0));
+        assert!((signed_volume(&triangles) - 4.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,221 @@
+//! Density volumes from raw `u8`/`u16` files and from stacks of slice images.
+//!
+//! Raw files hold `ImportOptions::volume_size` samples, x varying fastest
+//! and z slowest. The sample type follows from the file length, 16 bit
+//! samples are little endian. A directory is read as a stack of PGM or PNG
+//! slices ordered by file name, each slice one step along z.
+//!
+//! The volume's z axis points up, so slices are stacked along the world's y.
+//! Samples denser than `ImportOptions::threshold` become solid, either all
+//! of `ImportOptions::material` or split into `ImportOptions::bands` grey
+//! materials from dark to light.
+
+use std::path::Path;
+
+use nalgebra::Vector3;
+
+use super::{cells::MAX_GRID_CELLS, heightmap, srgb_material, FormatError, ImportOptions};
+use crate::program::{palette::Palette, voxelbuffer::VoxelBuffer};
+
+pub struct Volume {
+    size: Vector3<u32>,
+    /// densities scaled to the full `u16` range, x varying fastest
+    samples: Vec<u16>,
+}
+
+/// Number of samples in a volume of `size`, if it is not too large.
+fn sample_count(size: Vector3<u32>) -> Result<usize, FormatError> {
+    let count = size.iter().map(|&s| u64::from(s)).product::<u64>();
+    if count > MAX_GRID_CELLS {
+        return Err(FormatError::Unsupported(format!(
+            "{}x{}x{} samples are too many",
+            size.x, size.y, size.z
+        )));
+    }
+    Ok(count as usize)
+}
+
+pub fn read_raw(path: &Path, size: Option<Vector3<u32>>) -> Result<Volume, FormatError> {
+    let size = size.ok_or_else(|| FormatError::Invalid("raw volumes need a volume size".into()))?;
+    parse_raw(&std::fs::read(path)?, size)
+}
+
+pub fn parse_raw(data: &[u8], size: Vector3<u32>) -> Result<Volume, FormatError> {
+    let count = sample_count(size)?;
+    let samples = if data.len() == count {
+        data.iter().map(|&s| u16::from(s) * 257).collect()
+    } else if data.len() == 2 * count {
+        data.chunks_exact(2)
+            .map(|s| u16::from_le_bytes([s[0], s[1]]))
+            .collect()
+    } else {
+        return Err(FormatError::Invalid(format!(
+            "raw volume of {}x{}x{} samples needs {count} or {} bytes, the file has {}",
+            size.x,
+            size.y,
+            size.z,
+            2 * count,
+            data.len()
+        )));
+    };
+    Ok(Volume { size, samples })
+}
+
+/// Reads every PGM and PNG image in `dir` as one slice of the volume.
+pub fn read_slices(dir: &Path) -> Result<Volume, FormatError> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        if matches!(extension.as_deref(), Some("pgm" | "png")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    if paths.is_empty() {
+        return Err(FormatError::Invalid(format!(
+            "no pgm or png slices in {}",
+            dir.display()
+        )));
+    }
+
+    let mut volume = Volume {
+        size: Vector3::zeros(),
+        samples: Vec::new(),
+    };
+    for path in &paths {
+        let slice = if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("png"))
+        {
+            heightmap::read_png(path)?
+        } else {
+            heightmap::read_pgm(path)?
+        };
+        let slice_size = [slice.width, slice.height].map(|s| s as u32);
+        if volume.samples.is_empty() {
+            volume.size = Vector3::new(slice_size[0], slice_size[1], paths.len() as u32);
+            let count = sample_count(volume.size)?;
+            volume.samples.reserve_exact(count);
+        } else if slice_size != [volume.size.x, volume.size.y] {
+            return Err(FormatError::Invalid(format!(
+                "slice {} is not {}x{} pixels",
+                path.display(),
+                volume.size.x,
+                volume.size.y
+            )));
+        }
+        volume.samples.extend(
+            slice
+                .samples
+                .iter()
+                .map(|&s| (s.clamp(0.0, 1.0) * f32::from(u16::MAX)).round() as u16),
+        );
+    }
+    Ok(volume)
+}
+
+impl Volume {
+    /// Writes the dense samples into the world and returns the number of
+    /// leaves inserted.
+    pub fn insert(
+        &self,
+        world: &mut VoxelBuffer,
+        palette: &mut Palette,
+        options: &ImportOptions,
+    ) -> Result<u64, FormatError> {
+        let threshold = (options.threshold.clamp(0.0, 1.0) * f64::from(u16::MAX)) as u32;
+        let first_band = palette.material_count();
+        for band in 0..options.bands {
+            let grey = ((band + 1) * 255 / options.bands) as u8;
+            palette.set(first_band + band, srgb_material([grey; 3]));
+        }
+
+        let size = self.size.map(|s| s as usize);
+        let leaves = super::cells::insert_cells(world, options.placement, self.size.xzy(), |p| {
+            let i = p.x as usize + size.x * (p.z as usize + size.y * p.y as usize);
+            let density = u32::from(self.samples[i]);
+            if density <= threshold {
+                return None;
+            }
+            if options.bands == 0 {
+                return Some(options.material);
+            }
+            // equal slices of the range above the threshold
+            let band = u64::from(density - threshold - 1) * u64::from(options.bands)
+                / u64::from(u32::from(u16::MAX) - threshold);
+            Some(first_band + band as u32)
+        })?;
+        log::info!(
+            "imported volume of {}x{}x{} samples, {} leaves",
+            self.size.x,
+            self.size.y,
+            self.size.z,
+            leaves
+        );
+        Ok(leaves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::parse_raw;
+    use crate::program::{
+        formats::{ImportOptions, Placement},
+        palette::Palette,
+        voxelbuffer::VoxelBuffer,
+    };
+
+    fn options(level: u32) -> ImportOptions {
+        ImportOptions {
+            placement: Placement {
+                origin: Vector3::zeros(),
+                level,
+            },
+            material: 2,
+            pixel_size: 1.0,
+            max_height: 1,
+            scale: 1.0,
+            solid: false,
+            volume_size: None,
+            threshold: 0.5,
+            bands: 0,
+        }
+    }
+
+    #[test]
+    fn slices_stack_along_y() {
+        let mut data = [0; 6];
+        // sample x 1, y 0, z 2 of a 2x1x3 volume
+        data[5] = 200;
+        let volume = parse_raw(&data, Vector3::new(2, 1, 3)).unwrap();
+        let mut world = VoxelBuffer::empty();
+        let options = options(3);
+        let leaves = volume
+            .insert(&mut world, &mut Palette::new(), &options)
+            .unwrap();
+        assert_eq!(leaves, 1);
+        let side = options.placement.cell_side();
+        let voxel = world.get(Vector3::new(1, 2, 0) * side).unwrap();
+        assert_eq!((voxel.level, voxel.material), (3, 2));
+    }
+
+    #[test]
+    fn volume_filling_the_whole_octree() {
+        let volume = parse_raw(&[255; 64], Vector3::repeat(4)).unwrap();
+        let mut world = VoxelBuffer::empty();
+        let leaves = volume
+            .insert(&mut world, &mut Palette::new(), &options(1))
+            .unwrap();
+        assert_eq!(leaves, 8);
+        assert_eq!(
+            world.count_in_box(Vector3::zeros(), Vector3::repeat(u32::MAX)),
+            1 << 96
+        );
+    }
+}
@@ -0,0 +1,635 @@
+//! MagicaVoxel `.vox` files.
+//!
+//! A file is `"VOX "`, a version and a `MAIN` chunk whose children hold the
+//! content. Every chunk is an id, the byte sizes of its content and of its
+//! children, and then both. Models are a `SIZE` chunk followed by an `XYZI`
+//! chunk, the colours live in an optional `RGBA` chunk.
+//!
+//! The `nTRN`, `nGRP` and `nSHP` chunks form a scene graph placing the models,
+//! only its translations are applied. Files without one get their models side
+//! by side along x. Material and layer chunks are skipped.
+//!
+//! MagicaVoxel uses z as the up axis, voxels are stored with y and z swapped.
+
+use std::{collections::HashMap, path::Path};
+
+use nalgebra::Vector3;
+
+use super::{material_srgb, srgb_material, FormatError, Placement};
+use crate::program::{
+    palette::Palette,
+    voxelbuffer::{Overwrite, VoxelBuffer},
+};
+
+const MAGIC: [u8; 4] = *b"VOX ";
+const VERSION: u32 = 150;
+/// Largest model extent MagicaVoxel accepts on each axis.
+const MAX_MODEL_SIZE: u32 = 256;
+/// Scene graph nesting beyond this is treated as a cycle.
+const MAX_SCENE_DEPTH: usize = 64;
+
+pub struct VoxModel {
+    /// extent in MagicaVoxel coordinates
+    pub size: Vector3<u32>,
+    /// position in MagicaVoxel coordinates and colour index, 1..=255
+    pub voxels: Vec<([u8; 3], u8)>,
+}
+
+/// A model placed in the scene.
+pub struct VoxInstance {
+    pub model: usize,
+    /// minimum corner in MagicaVoxel coordinates
+    pub pos: Vector3<i32>,
+}
+
+pub struct VoxScene {
+    pub models: Vec<VoxModel>,
+    pub instances: Vec<VoxInstance>,
+    /// RGBA of colour index `i + 1` at `i`, index 0 means empty
+    pub palette: [[u8; 4]; 256],
+}
+
+enum SceneNode {
+    Transform {
+        child: i32,
+        translation: Vector3<i32>,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        models: Vec<i32>,
+    },
+}
+
+/// Little endian cursor over the file content.
+struct Bytes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        if n > self.data.len() {
+            return Err(FormatError::Invalid(
+                "vox chunk extends past the end".into(),
+            ));
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, FormatError> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn id(&mut self) -> Result<[u8; 4], FormatError> {
+        Ok(self.take(4)?.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> Result<&'a str, FormatError> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?)
+            .map_err(|_| FormatError::Invalid("vox string is not utf-8".into()))
+    }
+
+    fn dict(&mut self) -> Result<HashMap<&'a str, &'a str>, FormatError> {
+        let len = self.u32()?;
+        (0..len)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+
+    /// Counts are never negative, this also catches absurd values early.
+    fn count(&mut self) -> Result<usize, FormatError> {
+        let count = self.u32()? as usize;
+        if count > self.data.len() {
+            return Err(FormatError::Invalid("vox count exceeds chunk size".into()));
+        }
+        Ok(count)
+    }
+}
+
+pub fn read(path: &Path) -> Result<VoxScene, FormatError> {
+    parse(&std::fs::read(path)?)
+}
+
+pub fn parse(data: &[u8]) -> Result<VoxScene, FormatError> {
+    let mut bytes = Bytes { data };
+    if bytes.id()? != MAGIC {
+        return Err(FormatError::Invalid("not a vox file".into()));
+    }
+    let _version = bytes.u32()?;
+
+    if &bytes.id()? != b"MAIN" {
+        return Err(FormatError::Invalid("vox file has no MAIN chunk".into()));
+    }
+    let content_size = bytes.u32()? as usize;
+    let children_size = bytes.u32()? as usize;
+    bytes.take(content_size)?;
+    let mut children = Bytes {
+        data: bytes.take(children_size)?,
+    };
+
+    let mut scene = VoxScene {
+        models: Vec::new(),
+        instances: Vec::new(),
+        palette: default_palette(),
+    };
+    let mut nodes = HashMap::new();
+    let mut size = None;
+    while !children.data.is_empty() {
+        let id = children.id()?;
+        let content_size = children.u32()? as usize;
+        let children_size = children.u32()? as usize;
+        let mut content = Bytes {
+            data: children.take(content_size)?,
+        };
+        children.take(children_size)?;
+
+        match &id {
+            b"SIZE" => {
+                size = Some(Vector3::new(content.u32()?, content.u32()?, content.u32()?));
+            }
+            b"XYZI" => {
+                let size = size
+                    .take()
+                    .ok_or_else(|| FormatError::Invalid("XYZI chunk without SIZE".into()))?;
+                let count = content.u32()? as usize;
+                let voxels = content
+                    .take(4 * count)?
+                    .chunks_exact(4)
+                    .map(|v| ([v[0], v[1], v[2]], v[3]))
+                    .collect();
+                scene.models.push(VoxModel { size, voxels });
+            }
+            b"RGBA" => {
+                for entry in &mut scene.palette {
+                    *entry = content.take(4)?.try_into().unwrap();
+                }
+            }
+            b"nTRN" => {
+                let id = content.i32()?;
+                content.dict()?;
+                let child = content.i32()?;
+                let _reserved = content.i32()?;
+                let _layer = content.i32()?;
+                let frames = content.count()?;
+                // animations are not supported, the first frame is used
+                let mut translation = Vector3::zeros();
+                for frame in 0..frames {
+                    let attributes = content.dict()?;
+                    if frame == 0 {
+                        if let Some(t) = attributes.get("_t") {
+                            translation = parse_translation(t)?;
+                        }
+                    }
+                }
+                nodes.insert(id, SceneNode::Transform { child, translation });
+            }
+            b"nGRP" => {
+                let id = content.i32()?;
+                content.dict()?;
+                let count = content.count()?;
+                let children = (0..count)
+                    .map(|_| content.i32())
+                    .collect::<Result<_, _>>()?;
+                nodes.insert(id, SceneNode::Group { children });
+            }
+            b"nSHP" => {
+                let id = content.i32()?;
+                content.dict()?;
+                let count = content.count()?;
+                let mut models = Vec::with_capacity(count);
+                for _ in 0..count {
+                    models.push(content.i32()?);
+                    content.dict()?;
+                }
+                nodes.insert(id, SceneNode::Shape { models });
+            }
+            _ => {}
+        }
+    }
+
+    if nodes.is_empty() {
+        let mut x = 0;
+        for (model, m) in scene.models.iter().enumerate() {
+            scene.instances.push(VoxInstance {
+                model,
+                pos: Vector3::new(x, 0, 0),
+            });
+            x += m.size.x as i32;
+        }
+    } else {
+        scene.place(&nodes, 0, Vector3::zeros(), 0)?;
+    }
+    Ok(scene)
+}
+
+fn parse_translation(t: &str) -> Result<Vector3<i32>, FormatError> {
+    let coords: Vec<i32> = t
+        .split_whitespace()
+        .map(|c| c.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| FormatError::Invalid(format!("bad vox translation {t:?}")))?;
+    <[i32; 3]>::try_from(coords)
+        .map(Vector3::from)
+        .map_err(|_| FormatError::Invalid(format!("bad vox translation {t:?}")))
+}
+
+impl VoxScene {
+    /// Adds instances for every shape below scene graph node `id`.
+    fn place(
+        &mut self,
+        nodes: &HashMap<i32, SceneNode>,
+        id: i32,
+        translation: Vector3<i32>,
+        depth: usize,
+    ) -> Result<(), FormatError> {
+        if depth > MAX_SCENE_DEPTH {
+            return Err(FormatError::Invalid("vox scene graph is cyclic".into()));
+        }
+        match nodes.get(&id) {
+            Some(SceneNode::Transform {
+                child,
+                translation: t,
+            }) => {
+                self.place(nodes, *child, translation + t, depth + 1)?;
+            }
+            Some(SceneNode::Group { children }) => {
+                for &child in children {
+                    self.place(nodes, child, translation, depth + 1)?;
+                }
+            }
+            Some(SceneNode::Shape { models }) => {
+                for &model in models {
+                    let size = usize::try_from(model)
+                        .ok()
+                        .and_then(|m| self.models.get(m))
+                        .map(|m| m.size)
+                        .ok_or_else(|| {
+                            FormatError::Invalid(format!("missing vox model {model}"))
+                        })?;
+                    // translations point at the centre of a model
+                    self.instances.push(VoxInstance {
+                        model: model as usize,
+                        pos: translation - size.map(|s| (s / 2) as i32),
+                    });
+                }
+            }
+            None => return Err(FormatError::Invalid(format!("missing vox scene node {id}"))),
+        }
+        Ok(())
+    }
+
+    /// Writes every placed model into the world, the minimum corner of the
+    /// scene at `placement.origin`. Each used colour becomes a new material
+    /// appended to `palette`. Returns the number of voxels written.
+    pub fn insert(
+        &self,
+        world: &mut VoxelBuffer,
+        palette: &mut Palette,
+        placement: Placement,
+    ) -> Result<u64, FormatError> {
+        let Some(scene_min) = self
+            .instances
+            .iter()
+            .map(|i| i.pos)
+            .reduce(|a, b| a.inf(&b))
+        else {
+            return Ok(0);
+        };
+
+        let mut materials: HashMap<u8, u32> = HashMap::new();
+        let mut count = 0;
+        for instance in &self.instances {
+            let offset = (instance.pos - scene_min).map(|c| c as u32);
+            for &([x, y, z], color) in &self.models[instance.model].voxels {
+                if color == 0 {
+                    continue;
+                }
+                let material = *materials.entry(color).or_insert_with(|| {
+                    let [r, g, b, a] = self.palette[color as usize - 1];
+                    let id = palette.material_count();
+                    let mut material = srgb_material([r, g, b]);
+                    material.transparency = 1.0 - f32::from(a) / 255.0;
+                    palette.set(id, material);
+                    id
+                });
+                let v = offset + Vector3::new(u32::from(x), u32::from(y), u32::from(z));
+                let pos = placement.cell_pos(v.xzy()).ok_or_else(|| {
+                    FormatError::Invalid("vox model does not fit into the world".into())
+                })?;
+                world.add_voxel_with(pos, placement.level, material, Overwrite::Replace)?;
+                count += 1;
+            }
+        }
+        log::info!(
+            "imported {} vox models, {count} voxels, {} materials",
+            self.instances.len(),
+            materials.len()
+        );
+        Ok(count)
+    }
+
+    /// Samples the `size` cells at `placement` into a scene. A cell is solid
+    /// if any voxel inside it is, taking the material of the first one found.
+    /// Regions larger than a MagicaVoxel model are split into several.
+    pub fn extract(
+        world: &VoxelBuffer,
+        palette: &Palette,
+        placement: Placement,
+        size: Vector3<u32>,
+    ) -> Result<Self, FormatError> {
+        let too_large = || FormatError::Invalid("export region does not fit into the world".into());
+        if size.iter().any(|&s| s == 0) {
+            return Err(FormatError::Invalid("export region is empty".into()));
+        }
+        let min = placement.origin;
+        let max = placement
+            .cell_pos(size.map(|s| s - 1))
+            .ok_or_else(too_large)?
+            .add_scalar(placement.cell_side() - 1);
+
+        // colour indices in order of material ID, so exports are reproducible
+        let mut used = Vec::new();
+        world.for_each_leaf(min, max, |_, _, material| used.push(material));
+        used.sort_unstable();
+        used.dedup();
+        if used.len() > 255 {
+            return Err(FormatError::Unsupported(format!(
+                "{} materials in the region, vox files hold at most 255",
+                used.len()
+            )));
+        }
+        let mut scene = VoxScene {
+            models: Vec::new(),
+            instances: Vec::new(),
+            palette: [[0; 4]; 256],
+        };
+        let mut colors = HashMap::new();
+        for (i, &material) in used.iter().enumerate() {
+            scene.palette[i] = material_srgb(&palette.get(material));
+            colors.insert(material, i as u8 + 1);
+        }
+
+        let shift = 31 - placement.level;
+        let tiles = size.map(|s| s.div_ceil(MAX_MODEL_SIZE));
+        for tz in 0..tiles.z {
+            for ty in 0..tiles.y {
+                for tx in 0..tiles.x {
+                    let tile = Vector3::new(tx, ty, tz) * MAX_MODEL_SIZE;
+                    let tile_size = (size - tile).map(|s| s.min(MAX_MODEL_SIZE));
+                    let tile_min = placement.cell_pos(tile).ok_or_else(too_large)?;
+                    let tile_max = placement
+                        .cell_pos(tile + tile_size.map(|s| s - 1))
+                        .ok_or_else(too_large)?
+                        .add_scalar(placement.cell_side() - 1);
+
+                    let index =
+                        |c: Vector3<u32>| (c.x + tile_size.x * (c.y + tile_size.y * c.z)) as usize;
+                    let mut cells = vec![0_u8; tile_size.product() as usize];
+                    world.for_each_leaf(tile_min, tile_max, |lo, hi, material| {
+                        let lo = (lo - tile_min).map(|c| c >> shift);
+                        let hi = (hi - tile_min).map(|c| c >> shift);
+                        for z in lo.z..=hi.z {
+                            for y in lo.y..=hi.y {
+                                for x in lo.x..=hi.x {
+                                    let cell = &mut cells[index(Vector3::new(x, y, z))];
+                                    if *cell == 0 {
+                                        *cell = colors[&material];
+                                    }
+                                }
+                            }
+                        }
+                    });
+
+                    let mut voxels = Vec::new();
+                    for z in 0..tile_size.z {
+                        for y in 0..tile_size.y {
+                            for x in 0..tile_size.x {
+                                let color = cells[index(Vector3::new(x, y, z))];
+                                if color != 0 {
+                                    voxels.push(([x as u8, z as u8, y as u8], color));
+                                }
+                            }
+                        }
+                    }
+                    // the first model is kept even if empty, it anchors the
+                    // minimum corner when importing again
+                    if voxels.is_empty() && tile != Vector3::zeros() {
+                        continue;
+                    }
+                    scene.instances.push(VoxInstance {
+                        model: scene.models.len(),
+                        pos: tile.xzy().map(|c| c as i32),
+                    });
+                    scene.models.push(VoxModel {
+                        size: tile_size.xzy(),
+                        voxels,
+                    });
+                }
+            }
+        }
+        Ok(scene)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), FormatError> {
+        let mut children = Vec::new();
+        for model in &self.models {
+            let mut size = Vec::new();
+            for s in model.size.iter() {
+                push_u32(&mut size, *s);
+            }
+            push_chunk(&mut children, b"SIZE", &size);
+
+            let mut xyzi = Vec::with_capacity(4 + 4 * model.voxels.len());
+            push_u32(&mut xyzi, model.voxels.len() as u32);
+            for &([x, y, z], color) in &model.voxels {
+                xyzi.extend_from_slice(&[x, y, z, color]);
+            }
+            push_chunk(&mut children, b"XYZI", &xyzi);
+        }
+
+        // root transform, a group and a transform and shape per instance
+        let mut node = Vec::new();
+        push_transform(&mut node, 0, 1, Vector3::zeros());
+        push_chunk(&mut children, b"nTRN", &node);
+        node.clear();
+        push_u32(&mut node, 1);
+        push_u32(&mut node, 0);
+        push_u32(&mut node, self.instances.len() as u32);
+        for i in 0..self.instances.len() {
+            push_u32(&mut node, 2 + 2 * i as u32);
+        }
+        push_chunk(&mut children, b"nGRP", &node);
+        for (i, instance) in self.instances.iter().enumerate() {
+            let id = 2 + 2 * i as u32;
+            let size = self.models[instance.model].size;
+            node.clear();
+            push_transform(
+                &mut node,
+                id,
+                id + 1,
+                instance.pos + size.map(|s| (s / 2) as i32),
+            );
+            push_chunk(&mut children, b"nTRN", &node);
+            node.clear();
+            push_u32(&mut node, id + 1);
+            push_u32(&mut node, 0);
+            push_u32(&mut node, 1);
+            push_u32(&mut node, instance.model as u32);
+            push_u32(&mut node, 0);
+            push_chunk(&mut children, b"nSHP", &node);
+        }
+
+        push_chunk(&mut children, b"RGBA", self.palette.as_flattened());
+
+        let mut file = Vec::with_capacity(20 + children.len());
+        file.extend_from_slice(&MAGIC);
+        push_u32(&mut file, VERSION);
+        file.extend_from_slice(b"MAIN");
+        push_u32(&mut file, 0);
+        push_u32(&mut file, children.len() as u32);
+        file.extend_from_slice(&children);
+        std::fs::write(path, file)?;
+
+        log::info!(
+            "exported {} vox models, {} voxels",
+            self.models.len(),
+            self.models.iter().map(|m| m.voxels.len()).sum::<usize>()
+        );
+        Ok(())
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    push_u32(out, content.len() as u32);
+    push_u32(out, 0);
+    out.extend_from_slice(content);
+}
+
+/// Content of an `nTRN` chunk with a single frame.
+fn push_transform(out: &mut Vec<u8>, id: u32, child: u32, translation: Vector3<i32>) {
+    push_u32(out, id);
+    push_u32(out, 0);
+    push_u32(out, child);
+    push_u32(out, u32::MAX);
+    push_u32(out, 0);
+    push_u32(out, 1);
+    let t = format!("{} {} {}", translation.x, translation.y, translation.z);
+    push_u32(out, 1);
+    for s in ["_t", &t] {
+        push_u32(out, s.len() as u32);
+        out.extend_from_slice(s.as_bytes());
+    }
+}
+
+/// Palette MagicaVoxel uses for files without an `RGBA` chunk: a 6x6x6 colour
+/// cube without black, then ramps of blue, green, red and grey.
+fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0; 4]; 256];
+    let mut entries = palette.iter_mut();
+    for r in CUBE {
+        for g in CUBE {
+            for b in CUBE {
+                if (r, g, b) != (0, 0, 0) {
+                    *entries.next().unwrap() = [r, g, b, 0xff];
+                }
+            }
+        }
+    }
+    for channel in [2, 1, 0] {
+        for v in RAMP {
+            let mut rgba = [0, 0, 0, 0xff];
+            rgba[channel] = v;
+            *entries.next().unwrap() = rgba;
+        }
+    }
+    for v in RAMP {
+        *entries.next().unwrap() = [v, v, v, 0xff];
+    }
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{material_srgb, read, VoxScene};
+    use crate::program::{
+        formats::Placement,
+        palette::Palette,
+        voxelbuffer::{Overwrite, VoxelBuffer},
+    };
+
+    #[test]
+    fn export_import_round_trip() {
+        let placement = Placement {
+            origin: Vector3::zeros(),
+            level: 3,
+        };
+        let size = Vector3::new(5, 3, 4);
+        let side = placement.cell_side();
+        let cell = |x, y, z| Vector3::new(x, y, z) * side;
+        let mut world = VoxelBuffer::empty();
+        world
+            .add_voxel_with(cell(0, 0, 0), 3, 1, Overwrite::Replace)
+            .unwrap();
+        world
+            .add_voxel_with(cell(4, 0, 1), 3, 1, Overwrite::Replace)
+            .unwrap();
+        world
+            .add_voxel_with(cell(2, 2, 3), 3, 4, Overwrite::Replace)
+            .unwrap();
+        // a larger leaf covering eight cells
+        world
+            .add_voxel_with(cell(2, 0, 2), 2, 5, Overwrite::Replace)
+            .unwrap();
+        let palette = Palette::new();
+
+        let path = std::env::temp_dir().join(format!("voxelcraft-vox-{}.vox", std::process::id()));
+        VoxScene::extract(&world, &palette, placement, size)
+            .unwrap()
+            .write(&path)
+            .unwrap();
+        let scene = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut imported = VoxelBuffer::empty();
+        let mut imported_palette = Palette::new();
+        let count = scene
+            .insert(&mut imported, &mut imported_palette, placement)
+            .unwrap();
+        assert_eq!(count, 11);
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let color = |world: &VoxelBuffer, palette: &Palette| {
+                        world
+                            .get(cell(x, y, z))
+                            .map(|v| material_srgb(&palette.get(v.material)))
+                    };
+                    assert_eq!(
+                        color(&imported, &imported_palette),
+                        color(&world, &palette),
+                        "cell {x} {y} {z}"
+                    );
+                }
+            }
+        }
+    }
+}
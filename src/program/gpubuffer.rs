@@ -0,0 +1,40 @@
+/// A storage buffer backing a CPU side array that can grow.
+///
+/// The buffer is created on the first upload and recreated, larger and
+/// zeroed, whenever the array outgrows it. Bind groups referring to the old
+/// buffer must then be rebuilt, and the owner has to upload its whole array
+/// again.
+pub struct GrowableBuffer {
+    label: &'static str,
+    buffer: Option<wgpu::Buffer>,
+}
+
+impl GrowableBuffer {
+    pub const fn new(label: &'static str) -> Self {
+        Self {
+            label,
+            buffer: None,
+        }
+    }
+
+    /// Makes sure the buffer holds at least `size` bytes. Returns `true` when
+    /// a new buffer was created.
+    pub fn reserve(&mut self, device: &wgpu::Device, size: u64) -> bool {
+        if self.buffer.as_ref().is_some_and(|b| b.size() >= size) {
+            return false;
+        }
+        self.buffer = Some(device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(self.label),
+            size,
+            mapped_at_creation: false,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        }));
+        true
+    }
+
+    pub fn get(&self) -> &wgpu::Buffer {
+        self.buffer
+            .as_ref()
+            .unwrap_or_else(|| panic!("{} has not been uploaded yet", self.label))
+    }
+}
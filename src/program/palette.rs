@@ -0,0 +1,136 @@
+use std::mem;
+
+use bytemuck::{Pod, Zeroable};
+
+use super::gpubuffer::GrowableBuffer;
+
+/// Appearance of a single material, indexed by the material ID stored in the
+/// upper bits of a leaf.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Material {
+    /// linear RGB in 0..=1
+    pub color: [f32; 3],
+    /// multiplier of `color` added on top of the lit colour
+    pub emission: f32,
+    /// 0 is mirror-like, 1 is fully diffuse
+    pub roughness: f32,
+    /// 0 is opaque, 1 shows only the background
+    pub transparency: f32,
+}
+
+impl Material {
+    pub const fn new(color: [f32; 3]) -> Self {
+        Self {
+            color,
+            emission: 0.0,
+            roughness: 1.0,
+            transparency: 0.0,
+        }
+    }
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self::new([0.8, 0.8, 0.8])
+    }
+}
+
+pub struct Palette {
+    gpu_buffer: GrowableBuffer,
+    materials: Vec<Material>,
+    dirty: bool,
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        let mut s = Self {
+            gpu_buffer: GrowableBuffer::new("palette buffer"),
+            materials: Vec::new(),
+            dirty: true,
+        };
+
+        // grass
+        s.set(0, Material::new([0.30, 0.55, 0.20]));
+        // stone
+        s.set(
+            1,
+            Material {
+                roughness: 0.8,
+                ..Material::new([0.50, 0.50, 0.52])
+            },
+        );
+        // sand
+        s.set(2, Material::new([0.85, 0.75, 0.50]));
+        // lamp
+        s.set(
+            3,
+            Material {
+                emission: 2.0,
+                ..Material::new([1.0, 0.8, 0.4])
+            },
+        );
+
+        s
+    }
+
+    /// Defines material `id`, filling any gap below it with the default
+    /// material.
+    pub fn set(&mut self, id: u32, material: Material) {
+        let id = id as usize;
+        if id >= self.materials.len() {
+            self.materials.resize(id + 1, Material::default());
+        }
+        self.materials[id] = material;
+        self.dirty = true;
+    }
+
+    /// Material `id`, the default material if it was never defined.
+    pub fn get(&self, id: u32) -> Material {
+        self.materials.get(id as usize).copied().unwrap_or_default()
+    }
+
+    /// Number of defined materials, also the first unused ID.
+    pub fn material_count(&self) -> u32 {
+        self.materials.len() as u32
+    }
+
+    /// Uploads the palette if it changed. Returns `true` when the GPU buffer
+    /// was recreated, see `GrowableBuffer`.
+    pub fn update_buffer(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        // storage bindings cannot be empty
+        let size = (mem::size_of::<MaterialData>() * self.materials.len().max(1)) as u64;
+        let recreated = self.gpu_buffer.reserve(device, size);
+        if recreated || self.dirty {
+            let data: Vec<MaterialData> = self.materials.iter().map(MaterialData::from).collect();
+            queue.write_buffer(self.buffer(), 0, bytemuck::cast_slice(&data));
+            self.dirty = false;
+        }
+        recreated
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        self.gpu_buffer.get()
+    }
+}
+
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C, align(16))]
+struct MaterialData {
+    color: [f32; 3],
+    emission: f32,
+    roughness: f32,
+    transparency: f32,
+    pad: [f32; 2],
+}
+
+impl From<&Material> for MaterialData {
+    fn from(m: &Material) -> Self {
+        Self {
+            color: m.color,
+            emission: m.emission,
+            roughness: m.roughness,
+            transparency: m.transparency,
+            pad: [0.0; 2],
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use nalgebra::Vector3;
+
+use super::{
+    camera::Camera,
+    voxelbuffer::{VoxelBuffer, VOXELS_PER_UNIT},
+};
+
+/// Side of a terrain block in world units, the player is sized in these.
+const BLOCK: f64 = 1.0 / 4096.0;
+
+const HALF_WIDTH: f64 = 0.3 * BLOCK;
+const HEIGHT: f64 = 1.8 * BLOCK;
+const EYE_HEIGHT: f64 = 1.6 * BLOCK;
+
+const WALK_SPEED: f64 = 4.3 * BLOCK;
+const JUMP_SPEED: f64 = 8.5 * BLOCK;
+const GRAVITY: f64 = 28.0 * BLOCK;
+const MAX_FALL_SPEED: f64 = 60.0 * BLOCK;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveMode {
+    /// no gravity or collision, moves along the camera axes
+    Fly,
+    /// box shaped body falling under gravity and sliding along voxels
+    Walk,
+}
+
+pub struct Player {
+    pub mode: MoveMode,
+    velocity: Vector3<f64>,
+    on_ground: bool,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Self {
+            mode: MoveMode::Fly,
+            velocity: Vector3::zeros(),
+            on_ground: false,
+        }
+    }
+
+    pub fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            MoveMode::Fly => MoveMode::Walk,
+            MoveMode::Walk => MoveMode::Fly,
+        };
+        self.velocity = Vector3::zeros();
+        self.on_ground = false;
+        log::info!("movement mode {:?}", self.mode);
+    }
+
+    /// Moves the camera by `dt` seconds of input. `input` is the controller
+    /// direction in camera space, positive y meaning up or jump.
+    pub fn update(
+        &mut self,
+        camera: &mut Camera,
+        input: Vector3<f64>,
+        dt: f64,
+        world: &VoxelBuffer,
+    ) {
+        match self.mode {
+            MoveMode::Fly => camera.transform(input * dt),
+            MoveMode::Walk => self.walk(camera, input, dt, world),
+        }
+    }
+
+    fn walk(&mut self, camera: &mut Camera, input: Vector3<f64>, dt: f64, world: &VoxelBuffer) {
+        let horizontal = camera.to_world(Vector3::new(input.x, 0.0, input.z));
+        self.velocity.x = horizontal.x * WALK_SPEED;
+        self.velocity.z = horizontal.z * WALK_SPEED;
+
+        if input.y > 0.0 && self.on_ground {
+            self.velocity.y = JUMP_SPEED;
+        }
+        self.velocity.y = (self.velocity.y - GRAVITY * dt).max(-MAX_FALL_SPEED);
+
+        self.on_ground = false;
+        // vertical first so walking off a ledge does not snag on its edge
+        for axis in [1, 0, 2] {
+            let delta = self.velocity[axis] * dt;
+            let moved = sweep(world, camera.pos, axis, delta);
+            camera.pos[axis] += moved;
+            if moved != delta {
+                if axis == 1 && delta < 0.0 {
+                    self.on_ground = true;
+                }
+                self.velocity[axis] = 0.0;
+            }
+        }
+    }
+}
+
+/// World space bounds of the body with its eyes at `eye`.
+fn body(eye: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let lo = eye - Vector3::new(HALF_WIDTH, EYE_HEIGHT, HALF_WIDTH);
+    let hi = eye + Vector3::new(HALF_WIDTH, HEIGHT - EYE_HEIGHT, HALF_WIDTH);
+    (lo, hi)
+}
+
+/// Whether the body with its eyes at `eye` overlaps the cube of the given
+/// level at `cell`. Blocks placed there would trap the body.
+pub fn body_overlaps(eye: Vector3<f64>, cell: Vector3<u32>, level: u32) -> bool {
+    let (lo, hi) = body(eye);
+    let side = f64::from(1_u32 << (31 - level));
+    (0..3).all(|i| {
+        let min = f64::from(cell[i]) / VOXELS_PER_UNIT;
+        let max = (f64::from(cell[i]) + side) / VOXELS_PER_UNIT;
+        min < hi[i] && lo[i] < max
+    })
+}
+
+/// Whether the world space box contains no solid voxels. Space outside the
+/// octree counts as empty.
+fn is_free(world: &VoxelBuffer, lo: Vector3<f64>, hi: Vector3<f64>) -> bool {
+    let max = u32::MAX as f64;
+    let to_voxel = |c: f64| (c * VOXELS_PER_UNIT).clamp(0.0, max);
+    // touching a voxel is fine, overlapping it is not
+    let min = lo.map(|c| to_voxel(c).floor() as u32);
+    let max = hi.map(|c| (to_voxel(c).ceil() as u32).saturating_sub(1));
+    world.is_region_empty(min, max)
+}
+
+/// How far the body at `eye` can move `delta` along `axis` before touching a
+/// solid voxel. The whole swept box is checked so thin walls cannot be
+/// skipped over.
+fn sweep(world: &VoxelBuffer, eye: Vector3<f64>, axis: usize, delta: f64) -> f64 {
+    let (lo, hi) = body(eye);
+    let swept = |t: f64| {
+        let (mut lo, mut hi) = (lo, hi);
+        if t < 0.0 {
+            lo[axis] += t;
+        } else {
+            hi[axis] += t;
+        }
+        is_free(world, lo, hi)
+    };
+
+    if delta == 0.0 || swept(delta) {
+        return delta;
+    }
+    // already stuck, e.g. after flying into terrain and starting to walk
+    if !swept(0.0) {
+        return delta;
+    }
+
+    let (mut free, mut blocked) = (0.0, delta);
+    for _ in 0..32 {
+        let mid = 0.5 * (free + blocked);
+        if swept(mid) {
+            free = mid;
+        } else {
+            blocked = mid;
+        }
+    }
+    free
+}
@@ -0,0 +1,85 @@
+use nalgebra::Vector3;
+
+use super::{camera::Camera, player::Player, voxelbuffer::VoxelBuffer};
+
+/// simulation ticks per second
+pub const TICK_RATE: f64 = 60.0;
+/// ticks run at most per frame, the rest of a long stall is dropped
+const MAX_TICKS_PER_FRAME: u32 = 8;
+
+/// State handed to tick callbacks.
+pub struct TickCtx<'a> {
+    /// seconds per tick, always `1 / TICK_RATE`
+    pub dt: f64,
+    /// controller direction in camera space
+    pub input: Vector3<f64>,
+    pub camera: &'a mut Camera,
+    pub player: &'a mut Player,
+    pub world: &'a mut VoxelBuffer,
+}
+
+type TickCallback = Box<dyn FnMut(&mut TickCtx)>;
+
+/// Runs the simulation at a fixed rate independent of the frame rate.
+///
+/// Frame time accumulates until whole ticks are available. What is left over
+/// is returned as an interpolation factor between the last two tick states.
+pub struct Ticker {
+    dt: f64,
+    accumulator: f64,
+    callbacks: Vec<TickCallback>,
+}
+
+impl Ticker {
+    pub fn new(tick_rate: f64) -> Self {
+        Self {
+            dt: 1.0 / tick_rate,
+            accumulator: 0.0,
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Registers a callback run once per tick, in registration order.
+    pub fn on_tick(&mut self, callback: impl FnMut(&mut TickCtx) + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Runs the ticks that fit into `frame_delta` plus the time left over from
+    /// previous frames. Returns how far the rendered state lies between the
+    /// previous and the current tick, from 0 to 1.
+    pub fn advance(
+        &mut self,
+        frame_delta: f64,
+        input: Vector3<f64>,
+        camera: &mut Camera,
+        player: &mut Player,
+        world: &mut VoxelBuffer,
+    ) -> f64 {
+        self.accumulator += frame_delta;
+
+        let mut ticks = 0;
+        while self.accumulator >= self.dt {
+            if ticks == MAX_TICKS_PER_FRAME {
+                log::debug!("dropping {:.3}s of simulation", self.accumulator);
+                self.accumulator %= self.dt;
+                break;
+            }
+            self.accumulator -= self.dt;
+            ticks += 1;
+
+            camera.begin_tick();
+            let mut ctx = TickCtx {
+                dt: self.dt,
+                input,
+                camera: &mut *camera,
+                player: &mut *player,
+                world: &mut *world,
+            };
+            for callback in &mut self.callbacks {
+                callback(&mut ctx);
+            }
+        }
+
+        self.accumulator / self.dt
+    }
+}
@@ -1,21 +1,105 @@
 use std::{
+    collections::HashMap,
     mem,
-    ops::{Index, IndexMut},
+    ops::{Index, IndexMut, Range},
 };
 
 use bytemuck::{Pod, Zeroable};
 use nalgebra::Vector3;
 
+use super::gpubuffer::GrowableBuffer;
+
+mod alloc;
+mod bulk;
+mod dag;
+mod normalize;
+mod query;
+mod raycast;
+mod save;
+
+pub use bulk::{morton_code, MAX_BULK_DEPTH};
+pub use query::Voxel;
+pub use raycast::RayHit;
+pub use save::WorldFileError;
+
+/// Largest storage buffer the renderer asks the device for, 0.5 GiB.
+pub const MAX_BUFFER_SIZE: u64 = 1 << 29;
+/// Node capacity a fresh buffer starts with, grown on demand.
+const INITIAL_NODES: usize = 1 << 10;
+const MAX_NODES: usize = MAX_BUFFER_SIZE as usize / mem::size_of::<OctreeNode>();
+/// voxel coordinates per world unit, the root spans 0..2 in world units
+pub const VOXELS_PER_UNIT: f64 = (1_u64 << 31) as f64;
+/// Materials are stored in the upper 30 bits of a leaf.
+pub const MAX_MATERIAL: u32 = (1 << 30) - 1;
+/// Past this many pending ranges the next upload just sends every node.
+const MAX_DIRTY_RANGES: usize = 4096;
+
 pub struct VoxelBuffer {
-    gpu_buffer: wgpu::Buffer,
+    gpu_buffer: GrowableBuffer,
     cpu_buffer: Vec<OctreeNode>,
     root: usize,
     root_level: u32,
-    // TODO: allocation
+    /// highest node index ever handed out
     bump_top: usize,
-    uploaded: bool,
+    /// head of the free node list, see `alloc.rs`
+    free_head: u32,
+    free_count: usize,
+    /// parent counts of nodes referenced more than once, see `dag.rs`
+    shared: HashMap<usize, u32>,
+    /// node ranges modified since the last upload, unsorted and possibly
+    /// overlapping
+    dirty: Vec<Range<usize>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelError {
+    /// every node slot of the buffer is in use
+    OutOfNodes { capacity: usize },
+    /// `Overwrite::Error` insertion into a cube that already has solid voxels
+    Conflict { pos: Vector3<u32>, size: u32 },
+}
+
+/// Node reached by `VoxelBuffer::descend`.
+struct Descent {
+    /// node holding the target cube
+    node: usize,
+    /// octant of the target cube in `node`
+    octant: Octant,
+    /// nodes walked through and the octant taken in each, for `collapse_path`
+    path: Vec<(usize, Octant)>,
+}
+
+/// What `add_voxel_with` does when the target cube already contains solid
+/// voxels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overwrite {
+    /// Replace the existing content. Finer detail inside the cube is freed
+    /// and a coarser leaf containing it is split around it.
+    #[default]
+    Replace,
+    /// Leave the world untouched.
+    Keep,
+    /// Leave the world untouched and return `VoxelError::Conflict`.
+    Error,
+}
+
+impl std::fmt::Display for VoxelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoxelError::OutOfNodes { capacity } => {
+                write!(f, "voxel buffer is full ({capacity} nodes)")
+            }
+            VoxelError::Conflict { pos, size } => write!(
+                f,
+                "voxel of size {size} at {:#010x} {:#010x} {:#010x} overlaps existing voxels",
+                pos.x, pos.y, pos.z
+            ),
+        }
+    }
 }
 
+impl std::error::Error for VoxelError {}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Zeroable, Pod)]
 pub struct OctreeNode {
@@ -47,6 +131,43 @@ impl IndexMut<Octant> for OctreeNode {
     }
 }
 
+impl Octant {
+    const ALL: [Octant; 8] = [
+        Octant::X0Y0Z0,
+        Octant::X1Y0Z0,
+        Octant::X0Y1Z0,
+        Octant::X1Y1Z0,
+        Octant::X0Y0Z1,
+        Octant::X1Y0Z1,
+        Octant::X0Y1Z1,
+        Octant::X1Y1Z1,
+    ];
+
+    /// octant selected by the top bit of each coordinate
+    fn from_pos(pos: Vector3<u32>) -> Self {
+        let top_bit = 1 << 31;
+        match (
+            pos.x & top_bit != 0,
+            pos.y & top_bit != 0,
+            pos.z & top_bit != 0,
+        ) {
+            (false, false, false) => Octant::X0Y0Z0,
+            (false, false, true) => Octant::X0Y0Z1,
+            (false, true, false) => Octant::X0Y1Z0,
+            (false, true, true) => Octant::X0Y1Z1,
+            (true, false, false) => Octant::X1Y0Z0,
+            (true, false, true) => Octant::X1Y0Z1,
+            (true, true, false) => Octant::X1Y1Z0,
+            (true, true, true) => Octant::X1Y1Z1,
+        }
+    }
+}
+
+/// Child value of a solid leaf made of `material`.
+const fn leaf(material: u32) -> u32 {
+    (material << 2) | 0b11
+}
+
 impl OctreeNode {
     const fn new() -> Self {
         Self { children: [0; 8] }
@@ -54,16 +175,22 @@ impl OctreeNode {
 }
 
 impl VoxelBuffer {
-    pub fn new(device: &wgpu::Device) -> Self {
-        let mut cpu_buffer = vec![OctreeNode::new(); 10_000_000];
-        let root = 0;
-        let gpu_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("voxel buffer descriptor"),
-            size: (mem::size_of::<OctreeNode>() * cpu_buffer.len()) as u64,
-            mapped_at_creation: false,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-        });
+    /// A world without any solid voxels.
+    pub(crate) fn empty() -> Self {
+        Self {
+            gpu_buffer: GrowableBuffer::new("voxel buffer"),
+            cpu_buffer: vec![OctreeNode::new(); INITIAL_NODES],
+            root: 0,
+            root_level: 0,
+            bump_top: 0,
+            free_head: 0,
+            free_count: 0,
+            shared: HashMap::new(),
+            dirty: Vec::new(),
+        }
+    }
 
+    pub fn new() -> Self {
         // cpu_buffer[root][Octant::X1Y1Z1] = 0b11;
         // cpu_buffer[root][Octant::X1Y1Z0] = (1 << 2) | 0b01;
         // // cpu_buffer[root as usize][Octant::X1Y0Z1] = 0b0;
@@ -91,14 +218,7 @@ impl VoxelBuffer {
 
         // cpu_buffer[4][Octant::X1Y0Z0] = 0b11;
 
-        let mut s = Self {
-            gpu_buffer,
-            cpu_buffer,
-            root,
-            root_level: 0,
-            bump_top: 0,
-            uploaded: false,
-        };
+        let mut s = Self::empty();
 
         use rand::{Rng, SeedableRng};
         let mut rng = rand::rngs::StdRng::from_seed([0; 32]);
@@ -107,7 +227,13 @@ impl VoxelBuffer {
             let x = rng.gen_range((0b01 << k)..(0b11 << k));
             let y = rng.gen_range((0b01 << k)..(0b11 << k));
             let z = rng.gen_range((0b01 << k)..(0b11 << k));
-            s.add_voxel(Vector3::new(x << (30 - k), y << (30 - k), z << (30 - k)), 8);
+            let material = (x ^ y ^ z) % 3 + 1;
+            s.add_voxel(
+                Vector3::new(x << (30 - k), y << (30 - k), z << (30 - k)),
+                8,
+                material,
+            )
+            .expect("demo world does not fit in the voxel buffer");
         }
         // use rand::{Rng, SeedableRng};
         // let mut rng = rand::rngs::StdRng::from_seed([0; 32]);
@@ -125,14 +251,18 @@ impl VoxelBuffer {
                 let zs = 2.0 * f64::from(z - (0b01 << k)) / f64::from(0b10 << k) - 1.0;
                 // let ys = 0.5 * (2.0 - xs*xs - zs*zs);
                 let ys = 0.5 * (1.0 - xs * zs);
-                s.add_voxel(
+                // keep the cubes intact where the surface passes through them
+                s.add_voxel_with(
                     Vector3::new(
                         x << (30 - k),
                         ((ys * f64::from(0b10 << k) + f64::from(0b01 << k)) as u32) << (30 - k),
                         z << (30 - k),
                     ),
                     k + 1,
-                );
+                    0,
+                    Overwrite::Keep,
+                )
+                .expect("demo world does not fit in the voxel buffer");
             }
         }
 
@@ -145,66 +275,205 @@ impl VoxelBuffer {
         s
     }
 
-    fn add_voxel(&mut self, mut pos: Vector3<u32>, size: u32) {
-        let mut cur_ocnode_idx = self.root as usize;
-        let top_bit = 1 << 31;
-        for _ in 0..size {
-            let idx = match (
-                pos.x & top_bit != 0,
-                pos.y & top_bit != 0,
-                pos.z & top_bit != 0,
-            ) {
-                (false, false, false) => Octant::X0Y0Z0,
-                (false, false, true) => Octant::X0Y0Z1,
-                (false, true, false) => Octant::X0Y1Z0,
-                (false, true, true) => Octant::X0Y1Z1,
-                (true, false, false) => Octant::X1Y0Z0,
-                (true, false, true) => Octant::X1Y0Z1,
-                (true, true, false) => Octant::X1Y1Z0,
-                (true, true, true) => Octant::X1Y1Z1,
+    /// Fills the cube of the given size at `pos` with `material`, replacing
+    /// whatever was there.
+    fn add_voxel(&mut self, pos: Vector3<u32>, size: u32, material: u32) -> Result<(), VoxelError> {
+        self.add_voxel_with(pos, size, material, Overwrite::Replace)
+    }
+
+    /// Fills the cube of the given size at `pos` with `material`, resolving
+    /// overlaps with existing voxels according to `overwrite`.
+    ///
+    /// Nodes whose children end up as eight copies of the same leaf are
+    /// merged into a single leaf one level higher.
+    pub fn add_voxel_with(
+        &mut self,
+        pos: Vector3<u32>,
+        size: u32,
+        material: u32,
+        overwrite: Overwrite,
+    ) -> Result<(), VoxelError> {
+        debug_assert!(material <= MAX_MATERIAL);
+        if overwrite != Overwrite::Replace && self.has_solid(pos, size) {
+            return match overwrite {
+                Overwrite::Error => Err(VoxelError::Conflict { pos, size }),
+                _ => Ok(()),
             };
-            if self.cpu_buffer[cur_ocnode_idx][idx] == 0 {
-                self.bump_top += 1;
-                self.cpu_buffer[cur_ocnode_idx][idx] = 4 * (self.bump_top as u32) + 0b01;
-            } else if self.cpu_buffer[cur_ocnode_idx][idx] == 0b11 {
-                return;
-                panic!();
+        }
+
+        // a larger voxel of the same material already fills the cube
+        let Some(mut target) = self.descend(pos, size, |child| child == leaf(material))? else {
+            return Ok(());
+        };
+        let old = self.cpu_buffer[target.node][target.octant];
+        if old & 0b11 == 0b01 {
+            self.free_subtree(old as usize >> 2);
+        }
+        self.set_child(target.node, target.octant, leaf(material));
+        self.collapse_path(target.node, &mut target.path);
+        Ok(())
+    }
+
+    /// Walks from the root towards the cube of the given size at `pos`,
+    /// making every node on the way a unique branch: void children get a new
+    /// node, leaves are split into eight copies and shared nodes are copied.
+    ///
+    /// Returns `None` as soon as `stop` is true for a child on the way,
+    /// leaving it as it is.
+    fn descend(
+        &mut self,
+        mut pos: Vector3<u32>,
+        size: u32,
+        stop: impl Fn(u32) -> bool,
+    ) -> Result<Option<Descent>, VoxelError> {
+        let mut path = Vec::with_capacity(size as usize);
+        let mut cur_ocnode_idx = self.root;
+        for _ in 0..size {
+            let idx = Octant::from_pos(pos);
+            let child = self.cpu_buffer[cur_ocnode_idx][idx];
+            if stop(child) {
+                return Ok(None);
+            }
+            if child == 0 {
+                let new_idx = self.alloc_node()?;
+                self.set_child(cur_ocnode_idx, idx, 4 * (new_idx as u32) + 0b01);
+            } else if child & 0b11 == 0b11 {
+                let new_idx = self.alloc_node()?;
+                let split = OctreeNode {
+                    children: [child; 8],
+                };
+                self.set_node(new_idx, split);
+                self.set_child(cur_ocnode_idx, idx, 4 * (new_idx as u32) + 0b01);
             }
-            cur_ocnode_idx = self.cpu_buffer[cur_ocnode_idx][idx] as usize >> 2;
+            path.push((cur_ocnode_idx, idx));
+            cur_ocnode_idx = self.make_unique(cur_ocnode_idx, idx)?;
             pos.x <<= 1;
             pos.y <<= 1;
             pos.z <<= 1;
         }
-        let idx = match (
-            pos.x & top_bit != 0,
-            pos.y & top_bit != 0,
-            pos.z & top_bit != 0,
-        ) {
-            (false, false, false) => Octant::X0Y0Z0,
-            (false, false, true) => Octant::X0Y0Z1,
-            (false, true, false) => Octant::X0Y1Z0,
-            (false, true, true) => Octant::X0Y1Z1,
-            (true, false, false) => Octant::X1Y0Z0,
-            (true, false, true) => Octant::X1Y0Z1,
-            (true, true, false) => Octant::X1Y1Z0,
-            (true, true, true) => Octant::X1Y1Z1,
+        Ok(Some(Descent {
+            node: cur_ocnode_idx,
+            octant: Octant::from_pos(pos),
+            path,
+        }))
+    }
+
+    /// Whether the cube of the given size at `pos` contains any solid voxel.
+    fn has_solid(&self, mut pos: Vector3<u32>, size: u32) -> bool {
+        let mut cur_ocnode_idx = self.root;
+        for _ in 0..size {
+            let child = self.cpu_buffer[cur_ocnode_idx][Octant::from_pos(pos)];
+            if child & 0b11 != 0b01 {
+                // void or a leaf containing the whole cube
+                return child != 0;
+            }
+            cur_ocnode_idx = child as usize >> 2;
+            pos.x <<= 1;
+            pos.y <<= 1;
+            pos.z <<= 1;
+        }
+        self.subtree_has_solid(self.cpu_buffer[cur_ocnode_idx][Octant::from_pos(pos)])
+    }
+
+    fn subtree_has_solid(&self, child: u32) -> bool {
+        match child & 0b11 {
+            0b11 => true,
+            0b01 => self.cpu_buffer[child as usize >> 2]
+                .children
+                .iter()
+                .any(|&c| self.subtree_has_solid(c)),
+            _ => false,
+        }
+    }
+
+    /// Clears the cube of the given size at `pos`.
+    ///
+    /// Leaves containing the cube are split on the way down, and nodes
+    /// left without any children are released and unlinked from their
+    /// parent.
+    pub fn remove_voxel(&mut self, pos: Vector3<u32>, size: u32) -> Result<(), VoxelError> {
+        // nothing to clear below a void child
+        let Some(mut target) = self.descend(pos, size, |child| child == 0)? else {
+            return Ok(());
         };
-        self.cpu_buffer[cur_ocnode_idx][idx] = 0b11;
+        let old = self.cpu_buffer[target.node][target.octant];
+        if old & 0b11 == 0b01 {
+            self.free_subtree(old as usize >> 2);
+        }
+        self.set_child(target.node, target.octant, 0);
+        self.collapse_path(target.node, &mut target.path);
+        Ok(())
     }
 
-    pub fn update_buffer(&mut self, queue: &wgpu::Queue) {
-        if !self.uploaded {
+    fn set_child(&mut self, node_idx: usize, octant: Octant, value: u32) {
+        self.cpu_buffer[node_idx][octant] = value;
+        self.mark_dirty(node_idx);
+    }
+
+    fn set_node(&mut self, node_idx: usize, node: OctreeNode) {
+        self.cpu_buffer[node_idx] = node;
+        self.mark_dirty(node_idx);
+    }
+
+    fn mark_dirty(&mut self, node_idx: usize) {
+        if let Some(last) = self.dirty.last_mut() {
+            if last.contains(&node_idx) {
+                return;
+            } else if last.end == node_idx {
+                last.end += 1;
+                return;
+            }
+        }
+
+        if self.dirty.len() >= MAX_DIRTY_RANGES {
+            self.mark_all_dirty();
+        } else {
+            self.dirty.push(node_idx..node_idx + 1);
+        }
+    }
+
+    fn mark_all_dirty(&mut self) {
+        self.dirty.clear();
+        self.dirty.push(0..self.bump_top + 1);
+    }
+
+    /// Sorts the pending ranges and merges overlapping or touching ones.
+    fn take_dirty_ranges(&mut self) -> Vec<Range<usize>> {
+        let mut ranges = mem::take(&mut self.dirty);
+        ranges.sort_unstable_by_key(|r| r.start);
+
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    /// Uploads the nodes changed since the last call. Returns `true` when the
+    /// GPU buffer was recreated, see `GrowableBuffer`.
+    pub fn update_buffer(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        let size = (mem::size_of::<OctreeNode>() * self.cpu_buffer.len()) as u64;
+        let recreated = self.gpu_buffer.reserve(device, size);
+        if recreated {
+            // only nodes that were ever used matter
+            self.mark_all_dirty();
+            log::info!("voxel buffer reallocated, {} nodes", self.cpu_buffer.len());
+        }
+
+        for range in self.take_dirty_ranges() {
             queue.write_buffer(
-                &self.gpu_buffer,
-                0,
-                bytemuck::cast_slice(self.cpu_buffer.as_slice()),
+                self.buffer(),
+                (range.start * mem::size_of::<OctreeNode>()) as u64,
+                bytemuck::cast_slice(&self.cpu_buffer[range]),
             );
-            self.uploaded = true;
-            println!("uploading...");
         }
+        recreated
     }
 
     pub fn buffer(&self) -> &wgpu::Buffer {
-        &self.gpu_buffer
+        self.gpu_buffer.get()
     }
 }
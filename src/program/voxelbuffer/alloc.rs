@@ -0,0 +1,68 @@
+use super::{OctreeNode, VoxelBuffer, VoxelError, MAX_NODES};
+
+/// Free slots form a singly linked list threaded through the first child of
+/// each released node. The root is never released, so index 0 terminates the
+/// list.
+const FREE_LIST_END: u32 = 0;
+
+impl VoxelBuffer {
+    /// Returns an empty node slot, preferring previously released ones.
+    pub(super) fn alloc_node(&mut self) -> Result<usize, VoxelError> {
+        if self.free_head != FREE_LIST_END {
+            let idx = self.free_head as usize;
+            self.free_head = self.cpu_buffer[idx].children[0];
+            // the GPU still holds whatever the slot contained before it was freed
+            self.set_node(idx, OctreeNode::new());
+            self.free_count -= 1;
+            return Ok(idx);
+        }
+
+        if self.bump_top + 1 >= self.cpu_buffer.len() {
+            self.grow()?;
+        }
+        self.bump_top += 1;
+        Ok(self.bump_top)
+    }
+
+    /// Doubles the node storage, up to what fits in a single GPU buffer.
+    fn grow(&mut self) -> Result<(), VoxelError> {
+        let len = self.cpu_buffer.len();
+        if len >= MAX_NODES {
+            return Err(VoxelError::OutOfNodes { capacity: len });
+        }
+        self.cpu_buffer
+            .resize((2 * len).min(MAX_NODES), OctreeNode::new());
+        Ok(())
+    }
+
+    /// Puts a single node back on the free list. Its children are not visited.
+    ///
+    /// Released nodes are unreachable, so they are not marked for upload.
+    pub(super) fn free_node(&mut self, idx: usize) {
+        debug_assert_ne!(idx, self.root, "the root node cannot be freed");
+        debug_assert!(!self.shared.contains_key(&idx), "shared node freed");
+        self.cpu_buffer[idx] = OctreeNode::new();
+        self.cpu_buffer[idx].children[0] = self.free_head;
+        self.free_head = idx as u32;
+        self.free_count += 1;
+    }
+
+    /// Releases a node together with every node reachable from it. Shared
+    /// nodes only lose a reference and are kept alive for their other parents.
+    pub(super) fn free_subtree(&mut self, idx: usize) {
+        if self.release_shared(idx) {
+            return;
+        }
+        for child in self.cpu_buffer[idx].children {
+            if child & 0b11 == 0b01 {
+                self.free_subtree(child as usize >> 2);
+            }
+        }
+        self.free_node(idx);
+    }
+
+    /// Number of nodes currently in use, including the root.
+    pub fn node_count(&self) -> usize {
+        self.bump_top + 1 - self.free_count
+    }
+}
@@ -0,0 +1,180 @@
+use nalgebra::Vector3;
+
+use super::{leaf, Octant, OctreeNode, VoxelBuffer, VoxelError};
+
+/// Most levels `insert_sorted` can build below its cube, the most a 64 bit
+/// Morton code holds.
+pub const MAX_BULK_DEPTH: u32 = 21;
+
+/// Interleaves the bits of a cell position, x in the lowest bit. Sorting
+/// cells by their code visits them in the order of a depth first walk of
+/// the octree.
+pub fn morton_code(cell: Vector3<u32>) -> u64 {
+    // spreads the lower 21 bits of `c` to every third bit
+    let spread = |c: u32| {
+        let mut c = u64::from(c) & 0x1f_ffff;
+        c = (c | c << 32) & 0x001f_0000_0000_ffff;
+        c = (c | c << 16) & 0x001f_0000_ff00_00ff;
+        c = (c | c << 8) & 0x100f_00f0_0f00_f00f;
+        c = (c | c << 4) & 0x10c3_0c30_c30c_30c3;
+        (c | c << 2) & 0x1249_2492_4924_9249
+    };
+    spread(cell.x) | spread(cell.y) << 1 | spread(cell.z) << 2
+}
+
+impl VoxelBuffer {
+    /// Fills the cube of the given size at `pos` with leaves `depth` levels
+    /// below it, leaving the rest of the cube untouched.
+    ///
+    /// `leaves` holds the Morton code of each leaf relative to the cube
+    /// together with its material, in strictly increasing code order. The
+    /// subtree is built bottom-up in a single pass, keeping one open node
+    /// per level, and merged into the world afterwards. Returns the number
+    /// of leaves inserted.
+    pub fn insert_sorted(
+        &mut self,
+        pos: Vector3<u32>,
+        size: u32,
+        depth: u32,
+        leaves: impl IntoIterator<Item = (u64, u32)>,
+    ) -> Result<u64, VoxelError> {
+        debug_assert!(depth <= MAX_BULK_DEPTH && size + depth <= 31);
+
+        // open node of each level, with the code prefix of its position
+        let mut open: Vec<Option<(u64, OctreeNode)>> = vec![None; depth as usize];
+        let mut value = 0;
+        let mut count = 0;
+        let mut last = None;
+        for (code, material) in leaves {
+            debug_assert!(last < Some(code), "leaves are not sorted");
+            last = Some(code);
+            count += 1;
+            if depth == 0 {
+                value = leaf(material);
+            } else {
+                self.push_sorted(&mut open, 0, code, leaf(material))?;
+            }
+        }
+        for level in 0..open.len() {
+            if let Some((prefix, node)) = open[level].take() {
+                let finished = self.finish_node(node)?;
+                if level + 1 == open.len() {
+                    value = finished;
+                } else {
+                    self.push_sorted(&mut open, level + 1, prefix, finished)?;
+                }
+            }
+        }
+        if value == 0 {
+            return Ok(0);
+        }
+
+        let mut target = self
+            .descend(pos, size, |_| false)?
+            .expect("descent without a stop condition always arrives");
+        self.merge_child(target.node, target.octant, value)?;
+        self.collapse_path(target.node, &mut target.path);
+        Ok(count)
+    }
+
+    /// Stores `value` in the open node of `level` that `code` falls into.
+    /// A node the code has moved past is finished and handed to the level
+    /// above.
+    fn push_sorted(
+        &mut self,
+        open: &mut [Option<(u64, OctreeNode)>],
+        mut level: usize,
+        mut code: u64,
+        mut value: u32,
+    ) -> Result<(), VoxelError> {
+        loop {
+            let prefix = code >> 3;
+            let octant = (code & 0b111) as usize;
+            match &mut open[level] {
+                Some((open_prefix, node)) if *open_prefix == prefix => {
+                    node.children[octant] = value;
+                    return Ok(());
+                }
+                slot => {
+                    let mut node = OctreeNode::new();
+                    node.children[octant] = value;
+                    let Some((done_prefix, done)) = slot.replace((prefix, node)) else {
+                        return Ok(());
+                    };
+                    // the topmost node spans the whole cube and is never
+                    // left behind
+                    value = self.finish_node(done)?;
+                    code = done_prefix;
+                    level += 1;
+                }
+            }
+        }
+    }
+
+    /// Child value of a completed node: a single leaf if all of its
+    /// children are the same leaf, otherwise a branch to a new node.
+    fn finish_node(&mut self, node: OctreeNode) -> Result<u32, VoxelError> {
+        if let Some(value) = node.uniform_value() {
+            return Ok(value);
+        }
+        let idx = self.alloc_node()?;
+        self.set_node(idx, node);
+        Ok(4 * (idx as u32) + 0b01)
+    }
+
+    /// Merges the freshly built child value `new` into `octant` of the node
+    /// at `node_idx`. Solid parts of `new` replace what was there, empty
+    /// parts keep it. The nodes of `new` are taken over or released.
+    fn merge_child(&mut self, node_idx: usize, octant: Octant, new: u32) -> Result<(), VoxelError> {
+        let old = self.cpu_buffer[node_idx][octant];
+        match (old & 0b11, new & 0b11) {
+            (_, 0b00) => {}
+            (0b00, _) => self.set_child(node_idx, octant, new),
+            (0b01, 0b11) => {
+                self.free_subtree(old as usize >> 2);
+                self.set_child(node_idx, octant, new);
+            }
+            (_, 0b11) => self.set_child(node_idx, octant, new),
+            (0b11, _) => {
+                let value = self.fill_void(new as usize >> 2, old);
+                self.set_child(node_idx, octant, value);
+            }
+            _ => {
+                let idx = self.make_unique(node_idx, octant)?;
+                let new_idx = new as usize >> 2;
+                for child in Octant::ALL {
+                    self.merge_child(idx, child, self.cpu_buffer[new_idx][child])?;
+                }
+                self.free_node(new_idx);
+                if let Some(value) = self.cpu_buffer[idx].uniform_value() {
+                    self.free_node(idx);
+                    self.set_child(node_idx, octant, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the empty children in the unshared subtree at `idx` with
+    /// `fill` and returns the child value the subtree collapses to.
+    fn fill_void(&mut self, idx: usize, fill: u32) -> u32 {
+        for octant in Octant::ALL {
+            let child = self.cpu_buffer[idx][octant];
+            let value = match child & 0b11 {
+                0b00 => fill,
+                0b01 => self.fill_void(child as usize >> 2, fill),
+                _ => child,
+            };
+            if value != child {
+                self.set_child(idx, octant, value);
+            }
+        }
+        match self.cpu_buffer[idx].uniform_value() {
+            Some(value) => {
+                self.free_node(idx);
+                value
+            }
+            None => 4 * (idx as u32) + 0b01,
+        }
+    }
+}
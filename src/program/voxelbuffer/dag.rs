@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+
+use super::{Octant, VoxelBuffer, VoxelError};
+
+impl VoxelBuffer {
+    /// Merges identical subtrees into shared nodes, turning the tree into a
+    /// directed acyclic graph. Returns the number of nodes released.
+    ///
+    /// Subtrees are hashed bottom-up, so two nodes are merged exactly when
+    /// their children are equal after their own children have been merged.
+    /// Edits afterwards copy shared nodes on write, see `make_unique`.
+    pub fn compress_to_dag(&mut self) -> usize {
+        let before = self.node_count();
+        // uniform nodes would otherwise be merged instead of removed
+        self.normalize();
+
+        let mut canonical = HashMap::new();
+        let mut remap = HashMap::new();
+        let root = self.root;
+        let mut root_node = self.cpu_buffer[root];
+        for child in &mut root_node.children {
+            if *child & 0b11 == 0b01 {
+                let idx = self.dedup_subtree(*child as usize >> 2, &mut canonical, &mut remap);
+                *child = 4 * (idx as u32) + 0b01;
+            }
+        }
+        self.cpu_buffer[root] = root_node;
+
+        self.recount_shared();
+        self.mark_all_dirty();
+
+        let freed = before - self.node_count();
+        log::info!(
+            "compressed voxel buffer to a DAG, {} -> {} nodes",
+            before,
+            self.node_count()
+        );
+        freed
+    }
+
+    /// Returns the index of the canonical copy of the subtree at `idx`,
+    /// releasing `idx` if an identical subtree was seen before.
+    fn dedup_subtree(
+        &mut self,
+        idx: usize,
+        canonical: &mut HashMap<[u32; 8], usize>,
+        remap: &mut HashMap<usize, usize>,
+    ) -> usize {
+        // nodes that were already shared are reached more than once
+        if let Some(&new_idx) = remap.get(&idx) {
+            return new_idx;
+        }
+
+        let mut node = self.cpu_buffer[idx];
+        for child in &mut node.children {
+            if *child & 0b11 == 0b01 {
+                let child_idx = self.dedup_subtree(*child as usize >> 2, canonical, remap);
+                *child = 4 * (child_idx as u32) + 0b01;
+            }
+        }
+
+        let new_idx = match canonical.get(&node.children) {
+            Some(&existing) => {
+                self.shared.remove(&idx);
+                self.free_node(idx);
+                existing
+            }
+            None => {
+                self.cpu_buffer[idx] = node;
+                canonical.insert(node.children, idx);
+                idx
+            }
+        };
+        remap.insert(idx, new_idx);
+        new_idx
+    }
+
+    /// Rebuilds the parent counts of every node referenced more than once.
+    pub(super) fn recount_shared(&mut self) {
+        let mut counts: HashMap<usize, u32> = HashMap::new();
+        let mut stack = vec![self.root];
+        while let Some(idx) = stack.pop() {
+            for child in self.cpu_buffer[idx].children {
+                if child & 0b11 == 0b01 {
+                    let count = counts.entry(child as usize >> 2).or_insert(0);
+                    *count += 1;
+                    // visit each node once, on its first reference
+                    if *count == 1 {
+                        stack.push(child as usize >> 2);
+                    }
+                }
+            }
+        }
+        counts.retain(|_, count| *count > 1);
+        self.shared = counts;
+    }
+
+    /// Returns the node the branch at `octant` of `parent_idx` points to,
+    /// first replacing it with a private copy if other parents share it.
+    pub(super) fn make_unique(
+        &mut self,
+        parent_idx: usize,
+        octant: Octant,
+    ) -> Result<usize, VoxelError> {
+        let idx = self.cpu_buffer[parent_idx][octant] as usize >> 2;
+        if !self.shared.contains_key(&idx) {
+            return Ok(idx);
+        }
+
+        let copy_idx = self.alloc_node()?;
+        let node = self.cpu_buffer[idx];
+        for child in node.children {
+            if child & 0b11 == 0b01 {
+                *self.shared.entry(child as usize >> 2).or_insert(1) += 1;
+            }
+        }
+        self.release_shared(idx);
+        self.set_node(copy_idx, node);
+        self.set_child(parent_idx, octant, 4 * (copy_idx as u32) + 0b01);
+        Ok(copy_idx)
+    }
+
+    /// Drops one parent reference of a shared node. Returns `false` if the
+    /// node was not shared, meaning the caller holds the only reference.
+    pub(super) fn release_shared(&mut self, idx: usize) -> bool {
+        let Some(count) = self.shared.get_mut(&idx) else {
+            return false;
+        };
+        *count -= 1;
+        if *count == 1 {
+            self.shared.remove(&idx);
+        }
+        true
+    }
+}
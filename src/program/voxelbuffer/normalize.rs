@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use super::{Octant, OctreeNode, VoxelBuffer};
+
+impl OctreeNode {
+    /// Child value a node can be replaced with, if all of its children are
+    /// equal and none of them is a branch.
+    pub(super) fn uniform_value(&self) -> Option<u32> {
+        let first = self.children[0];
+        (first & 0b11 != 0b01 && self.children.iter().all(|&c| c == first)).then_some(first)
+    }
+}
+
+impl VoxelBuffer {
+    /// Walks back up an edit path starting at the node at `idx`, replacing
+    /// nodes whose children are all empty or all the same leaf with that
+    /// value in their parent. Every node on `path` must be unshared.
+    pub(super) fn collapse_path(&mut self, mut idx: usize, path: &mut Vec<(usize, Octant)>) {
+        while let Some(value) = self.cpu_buffer[idx].uniform_value() {
+            let Some((parent_idx, octant)) = path.pop() else {
+                break;
+            };
+            self.free_node(idx);
+            self.set_child(parent_idx, octant, value);
+            idx = parent_idx;
+        }
+    }
+
+    /// Collapses every uniform node in the tree into its parent. Returns the
+    /// number of nodes released.
+    pub fn normalize(&mut self) -> usize {
+        let before = self.node_count();
+
+        let mut memo = HashMap::new();
+        let root = self.root;
+        for octant in Octant::ALL {
+            let child = self.cpu_buffer[root][octant];
+            if child & 0b11 == 0b01 {
+                let value = self.normalize_subtree(child as usize >> 2, &mut memo);
+                if value != child {
+                    self.set_child(root, octant, value);
+                }
+            }
+        }
+
+        before - self.node_count()
+    }
+
+    /// Normalizes the subtree at `idx` and returns the child value its parent
+    /// should now hold. Called once for every reference to the node, so
+    /// shared nodes are released reference by reference.
+    fn normalize_subtree(&mut self, idx: usize, memo: &mut HashMap<usize, u32>) -> u32 {
+        let value = match memo.get(&idx) {
+            Some(&value) => value,
+            None => {
+                for octant in Octant::ALL {
+                    let child = self.cpu_buffer[idx][octant];
+                    if child & 0b11 == 0b01 {
+                        let value = self.normalize_subtree(child as usize >> 2, memo);
+                        if value != child {
+                            self.set_child(idx, octant, value);
+                        }
+                    }
+                }
+                let value = self.cpu_buffer[idx]
+                    .uniform_value()
+                    .unwrap_or(4 * (idx as u32) + 0b01);
+                memo.insert(idx, value);
+                value
+            }
+        };
+
+        if value & 0b11 != 0b01 {
+            self.free_subtree(idx);
+        }
+        value
+    }
+}
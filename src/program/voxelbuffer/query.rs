@@ -0,0 +1,218 @@
+use nalgebra::Vector3;
+
+use super::{Octant, VoxelBuffer};
+
+/// A solid leaf of the octree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Voxel {
+    /// minimum corner of the leaf
+    pub pos: Vector3<u32>,
+    /// number of descents from the root, the leaf spans `1 << (31 - level)`
+    pub level: u32,
+    pub material: u32,
+}
+
+impl VoxelBuffer {
+    /// Returns the leaf containing `pos`, or `None` if it is empty.
+    #[allow(dead_code)]
+    pub fn get(&self, pos: Vector3<u32>) -> Option<Voxel> {
+        let mut cur_ocnode_idx = self.root;
+        let mut shifted = pos;
+        for level in 0..32 {
+            let child = self.cpu_buffer[cur_ocnode_idx][Octant::from_pos(shifted)];
+            match child & 0b11 {
+                0b11 => {
+                    let mask = u32::MAX >> level >> 1;
+                    return Some(Voxel {
+                        pos: pos.map(|c| c & !mask),
+                        level,
+                        material: child >> 2,
+                    });
+                }
+                0b01 => cur_ocnode_idx = child as usize >> 2,
+                _ => return None,
+            }
+            shifted.x <<= 1;
+            shifted.y <<= 1;
+            shifted.z <<= 1;
+        }
+        None
+    }
+
+    /// Counts the solid unit cells in the box between `min` and `max`, both
+    /// inclusive. A leaf at level `l` contributes `1 << (3 * (31 - l))`.
+    #[allow(dead_code)]
+    pub fn count_in_box(&self, min: Vector3<u32>, max: Vector3<u32>) -> u128 {
+        let (min, max) = (min.map(u64::from), max.map(u64::from));
+        let mut count = 0;
+        self.visit_box(min, max, &mut |lo, hi, _| {
+            let extent = (hi - lo).add_scalar(1).map(u128::from);
+            count += extent.x * extent.y * extent.z;
+            true
+        });
+        count
+    }
+
+    /// Whether the box between `min` and `max`, both inclusive, has no solid
+    /// voxels.
+    pub fn is_region_empty(&self, min: Vector3<u32>, max: Vector3<u32>) -> bool {
+        let (min, max) = (min.map(u64::from), max.map(u64::from));
+        let mut empty = true;
+        self.visit_box(min, max, &mut |_, _, _| {
+            empty = false;
+            false
+        });
+        empty
+    }
+
+    /// Calls `f` with the clipped inclusive bounds and the material of every
+    /// leaf overlapping the box between `min` and `max`, both inclusive.
+    pub fn for_each_leaf(
+        &self,
+        min: Vector3<u32>,
+        max: Vector3<u32>,
+        mut f: impl FnMut(Vector3<u32>, Vector3<u32>, u32),
+    ) {
+        let (min, max) = (min.map(u64::from), max.map(u64::from));
+        self.visit_box(min, max, &mut |lo, hi, child| {
+            f(lo.map(|c| c as u32), hi.map(|c| c as u32), child >> 2);
+            true
+        });
+    }
+
+    /// Calls `f` with the clipped inclusive bounds and the child value of
+    /// every leaf overlapping the box, until it returns `false`.
+    fn visit_box(
+        &self,
+        min: Vector3<u64>,
+        max: Vector3<u64>,
+        f: &mut impl FnMut(Vector3<u64>, Vector3<u64>, u32) -> bool,
+    ) {
+        if min.zip_map(&max, |a, b| a > b).iter().any(|&b| b) {
+            return;
+        }
+        self.visit_node(self.root, Vector3::zeros(), 1 << 32, min, max, f);
+    }
+
+    fn visit_node(
+        &self,
+        idx: usize,
+        origin: Vector3<u64>,
+        side: u64,
+        min: Vector3<u64>,
+        max: Vector3<u64>,
+        f: &mut impl FnMut(Vector3<u64>, Vector3<u64>, u32) -> bool,
+    ) -> bool {
+        let half = side / 2;
+        for octant in Octant::ALL {
+            let child = self.cpu_buffer[idx][octant];
+            if child == 0 {
+                continue;
+            }
+            let bits = octant as u64;
+            let lo = origin + Vector3::new(bits & 1, (bits >> 1) & 1, (bits >> 2) & 1) * half;
+            let hi = lo.add_scalar(half - 1);
+            if (0..3).any(|i| hi[i] < min[i] || lo[i] > max[i]) {
+                continue;
+            }
+
+            let keep_going = if child & 0b11 == 0b11 {
+                f(lo.sup(&min), hi.inf(&max), child)
+            } else {
+                self.visit_node(child as usize >> 2, lo, half, min, max, f)
+            };
+            if !keep_going {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::Voxel;
+    use crate::program::voxelbuffer::VoxelBuffer;
+
+    /// Side of a leaf at `level`.
+    fn side(level: u32) -> u32 {
+        1 << (31 - level)
+    }
+
+    #[test]
+    fn empty_world() {
+        let world = VoxelBuffer::empty();
+        assert_eq!(world.get(Vector3::zeros()), None);
+        assert_eq!(world.get(Vector3::repeat(u32::MAX)), None);
+        assert_eq!(
+            world.count_in_box(Vector3::zeros(), Vector3::repeat(u32::MAX)),
+            0
+        );
+    }
+
+    #[test]
+    fn point_query_finds_added_leaf() {
+        let mut world = VoxelBuffer::empty();
+        let pos = Vector3::new(3, 1, 2) * side(3);
+        world.add_voxel(pos, 3, 5).unwrap();
+
+        let voxel = Voxel {
+            pos,
+            level: 3,
+            material: 5,
+        };
+        assert_eq!(world.get(pos), Some(voxel));
+        assert_eq!(world.get(pos.add_scalar(side(3) - 1)), Some(voxel));
+        assert_eq!(world.get(pos + Vector3::new(side(3), 0, 0)), None);
+        assert_eq!(world.get(pos - Vector3::new(0, 1, 0)), None);
+    }
+
+    #[test]
+    fn point_query_after_remove() {
+        let mut world = VoxelBuffer::empty();
+        let pos = Vector3::repeat(side(2));
+        world.add_voxel(pos, 2, 1).unwrap();
+        // the level 2 leaf is split to clear one of its children
+        let hole = pos + Vector3::new(side(3), 0, side(3));
+        world.remove_voxel(hole, 3).unwrap();
+
+        assert_eq!(world.get(hole), None);
+        assert_eq!(world.get(hole.add_scalar(side(3) - 1)), None);
+        assert_eq!(
+            world.get(pos),
+            Some(Voxel {
+                pos,
+                level: 3,
+                material: 1,
+            })
+        );
+
+        // filling the hole merges the children back into one leaf
+        world.add_voxel(hole, 3, 1).unwrap();
+        assert_eq!(world.get(hole).map(|v| (v.pos, v.level)), Some((pos, 2)));
+    }
+
+    #[test]
+    fn box_query_counts_cells() {
+        let mut world = VoxelBuffer::empty();
+        let pos = Vector3::repeat(side(4));
+        world.add_voxel(pos, 4, 2).unwrap();
+        world.remove_voxel(pos, 5).unwrap();
+
+        let whole = (side(4) as u128).pow(3);
+        let all = Vector3::repeat(u32::MAX);
+        assert_eq!(world.count_in_box(Vector3::zeros(), all), whole / 8 * 7);
+
+        // a box clipping the remaining leaves
+        let min = pos.add_scalar(side(5) - 2);
+        let max = pos.add_scalar(side(5) + 1);
+        assert_eq!(world.count_in_box(min, max), 4 * 4 * 4 - 2 * 2 * 2);
+        assert_eq!(world.count_in_box(max, min), 0);
+
+        world.remove_voxel(pos, 4).unwrap();
+        assert_eq!(world.count_in_box(Vector3::zeros(), all), 0);
+        assert_eq!(world.get(pos.add_scalar(side(4) - 1)), None);
+    }
+}
@@ -0,0 +1,414 @@
+use nalgebra::Vector3;
+
+use super::{OctreeNode, Voxel, VoxelBuffer, VOXELS_PER_UNIT};
+
+/// How a traversal ended, mirroring the `HIT_` constants in `shader.wgsl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOutcome {
+    /// the ray left the octree
+    Miss,
+    Leaf {
+        material: u32,
+        /// minimum corner of the leaf in voxel coordinates, rebuilt from the
+        /// traversal stack so it is exact even where `Trace::pos` is not
+        cell: Vector3<u32>,
+    },
+    /// the 1024 iteration budget ran out
+    StepLimit,
+}
+
+/// Result of `VoxelBuffer::shader_ray_cast`. Positions and distances are in
+/// the shader's coordinates, where the root spans 0..2 on every axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Trace {
+    pub outcome: TraceOutcome,
+    /// where the ray stopped
+    pub pos: Vector3<f32>,
+    pub dist: f32,
+    /// face the ray entered the leaf through, zero if it started inside it
+    pub normal: Vector3<f32>,
+    /// loop iterations taken
+    pub steps: u32,
+    /// octree level of the node being traversed when the ray stopped
+    pub level: u32,
+}
+
+/// Index of the subnode containing `cur`, in node-local coordinates 0..2.
+fn subnode_idx(cur: Vector3<f32>) -> u32 {
+    u32::from(cur.x > 1.0) | (u32::from(cur.y > 1.0) << 1) | (u32::from(cur.z > 1.0) << 2)
+}
+
+impl VoxelBuffer {
+    /// Casts a ray exactly like `ray_cast` in `shader.wgsl`, in the same
+    /// `f32` arithmetic, so the GPU traversal can be checked and debugged
+    /// on the CPU. Keep the two in sync.
+    pub fn shader_ray_cast(&self, origin: Vector3<f32>, dir_in: Vector3<f32>) -> Trace {
+        let mut total_dist = 0.0_f32;
+        let mut dist_mul = 1.0_f32;
+
+        // smallest positive normal number
+        // note that 1/m is a normal number
+        let m = f32::MIN_POSITIVE;
+        let dir = dir_in.map(|d| if -m < d && d < m { m } else { d });
+        let dir_inv = dir.map(|d| 1.0 / d);
+        let dir_pos = dir.map(|d| f32::from(u8::from(d > 0.0)));
+
+        let mut cur = origin;
+
+        let mut stack = [0_u32; 32];
+        let mut scale = 0_u32;
+        stack[0] = (self.root as u32) << 3;
+
+        let mut cur_ocnode: OctreeNode = self.cpu_buffer[self.root];
+        let mut cur_subnode_idx = subnode_idx(cur);
+
+        let mut ascensions_handled = true;
+        let mut normal = Vector3::zeros();
+
+        let trace = |outcome, total_dist: f32, normal, steps, level| Trace {
+            outcome,
+            pos: origin + dir * total_dist,
+            dist: total_dist,
+            normal,
+            steps,
+            level,
+        };
+
+        for i in 0..1024 {
+            let subnode_offset = Vector3::new(
+                f32::from(u8::from(cur_subnode_idx & 1 != 0)),
+                f32::from(u8::from(cur_subnode_idx & 2 != 0)),
+                f32::from(u8::from(cur_subnode_idx & 4 != 0)),
+            );
+            let cur_subnode_val = cur_ocnode.children[cur_subnode_idx as usize];
+
+            if !ascensions_handled {
+                cur = cur.zip_map(&subnode_offset, |c, o| 0.5_f32.mul_add(c, o));
+            }
+
+            if cur_subnode_val & 1 == 1 && ascensions_handled {
+                // subnode not void
+                if cur_subnode_val & 2 == 0 {
+                    // did not hit a leaf, descend
+                    stack[scale as usize] += cur_subnode_idx;
+
+                    cur_ocnode = self.cpu_buffer[cur_subnode_val as usize >> 2];
+
+                    cur -= subnode_offset;
+                    cur *= 2.0;
+                    scale += 1;
+                    dist_mul *= 0.5;
+
+                    cur_subnode_idx = subnode_idx(cur);
+                    stack[scale as usize] = (cur_subnode_val >> 2) << 3;
+                } else {
+                    // hit leaf
+                    let material = cur_subnode_val >> 2;
+                    let cell = (0..=scale).fold(Vector3::zeros(), |cell, level| {
+                        let octant = if level == scale {
+                            cur_subnode_idx
+                        } else {
+                            stack[level as usize] & 7
+                        };
+                        let bit = 1 << (31 - level);
+                        cell + Vector3::new(octant & 1, (octant >> 1) & 1, (octant >> 2) & 1) * bit
+                    });
+                    return trace(
+                        TraceOutcome::Leaf { material, cell },
+                        total_dist,
+                        normal,
+                        i + 1,
+                        scale,
+                    );
+                }
+            } else {
+                // subnode void
+
+                ascensions_handled = true;
+
+                // advance
+                let trgt = dir_pos + subnode_offset;
+                let d = (trgt - cur).component_mul(&dir_inv);
+
+                // pick minimum distance
+                let t = d.x.min(d.y).min(d.z);
+                let adv_axis = d.map(|v| v == t);
+
+                let neg = (0..3).any(|i| dir[i] < 0.0 && adv_axis[i]);
+
+                let idx_offset = u32::from(adv_axis.x)
+                    | (u32::from(adv_axis.y) << 1)
+                    | (u32::from(adv_axis.z) << 2);
+
+                cur = dir.zip_map(&cur, |d, c| t.mul_add(d, c));
+                total_dist = t.mul_add(dist_mul, total_dist);
+                normal = dir.zip_map(&adv_axis, |d, adv| if adv { -d.signum() } else { 0.0 });
+
+                if (cur_subnode_idx & idx_offset) != if neg { idx_offset } else { 0 } {
+                    // ascend, change coordinates
+                    if scale == 0 {
+                        return trace(TraceOutcome::Miss, total_dist, normal, i + 1, scale);
+                    }
+
+                    scale -= 1;
+                    dist_mul *= 2.0;
+                    let stack_top = stack[scale as usize];
+                    cur_ocnode = self.cpu_buffer[stack_top as usize >> 3];
+                    cur_subnode_idx = stack_top & 7;
+                    stack[scale as usize] = stack_top & !7;
+
+                    ascensions_handled = false;
+                } else {
+                    cur_subnode_idx ^= idx_offset;
+                }
+            }
+        }
+
+        trace(TraceOutcome::StepLimit, total_dist, normal, 1024, scale)
+    }
+}
+
+/// Side of a voxel cube, see `Voxel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Face {
+    NegX,
+    PosX,
+    NegY,
+    PosY,
+    NegZ,
+    PosZ,
+}
+
+impl Face {
+    fn from_normal(normal: Vector3<f32>) -> Option<Self> {
+        let faces = [
+            (Face::NegX, Face::PosX),
+            (Face::NegY, Face::PosY),
+            (Face::NegZ, Face::PosZ),
+        ];
+        (0..3).find(|&i| normal[i] != 0.0).map(|i| {
+            if normal[i] < 0.0 {
+                faces[i].0
+            } else {
+                faces[i].1
+            }
+        })
+    }
+
+    /// outward unit normal
+    pub fn normal(self) -> Vector3<i64> {
+        match self {
+            Face::NegX => -Vector3::x(),
+            Face::PosX => Vector3::x(),
+            Face::NegY => -Vector3::y(),
+            Face::PosY => Vector3::y(),
+            Face::NegZ => -Vector3::z(),
+            Face::PosZ => Vector3::z(),
+        }
+    }
+}
+
+/// Result of `VoxelBuffer::raycast`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RayHit {
+    pub voxel: Voxel,
+    /// face of `voxel` the ray entered through, `None` if it started inside
+    pub face: Option<Face>,
+    /// where the ray entered `voxel`, in voxel coordinates
+    pub pos: Vector3<f64>,
+    /// minimum corner of the cell of the same level just outside `face`,
+    /// which the ray passed through empty space to get out of
+    pub adjacent: Option<Vector3<u32>>,
+    /// along the normalized ray, in world units
+    pub dist: f64,
+}
+
+impl RayHit {
+    /// The cell at `level` inside the hit voxel that contains the point where
+    /// the ray entered it. Coarser levels give the cell containing the voxel.
+    pub fn cell(&self, level: u32) -> Vector3<u32> {
+        if level <= self.voxel.level {
+            let mask = !(u32::MAX >> level >> 1);
+            return self.voxel.pos.map(|c| c & mask);
+        }
+        let inside = match self.face {
+            Some(face) => self.pos - face.normal().cast::<f64>() * (cell_side(level) / 2.0),
+            None => self.pos,
+        };
+        cell_at(inside, level).unwrap_or(self.voxel.pos)
+    }
+
+    /// The cell at `level` just outside the entered face, next to where the
+    /// ray entered. `None` if the ray started inside the voxel or the cell
+    /// would lie outside the octree.
+    pub fn adjacent_at(&self, level: u32) -> Option<Vector3<u32>> {
+        adjacent_cell(self.pos, self.face?, level)
+    }
+}
+
+/// Side of a cell at `level` in voxel coordinates.
+fn cell_side(level: u32) -> f64 {
+    (1_u64 << (31 - level)) as f64
+}
+
+/// Minimum corner of the cell at `level` containing `pos`, in voxel
+/// coordinates.
+fn cell_at(pos: Vector3<f64>, level: u32) -> Option<Vector3<u32>> {
+    if pos
+        .iter()
+        .any(|&c| !(0.0..(1_u64 << 32) as f64).contains(&c))
+    {
+        return None;
+    }
+    let mask = !(u32::MAX >> level >> 1);
+    Some(pos.map(|c| c as u32 & mask))
+}
+
+/// The cell at `level` just outside `face`, next to the point `pos` on it.
+/// `None` if it would lie outside the octree.
+fn adjacent_cell(pos: Vector3<f64>, face: Face, level: u32) -> Option<Vector3<u32>> {
+    let outside = pos + face.normal().cast::<f64>() * (cell_side(level) / 2.0);
+    cell_at(outside, level)
+}
+
+impl VoxelBuffer {
+    /// Finds the first solid voxel along a ray in world coordinates, like the
+    /// camera's `pos` and `dir`, no further than `max_dist` away.
+    pub fn raycast(
+        &self,
+        origin: Vector3<f64>,
+        dir: Vector3<f64>,
+        max_dist: f64,
+    ) -> Option<RayHit> {
+        let dir = dir.normalize();
+        let trace = self.shader_ray_cast(origin.cast(), dir.cast());
+        let TraceOutcome::Leaf { material, cell } = trace.outcome else {
+            return None;
+        };
+        let dist = f64::from(trace.dist);
+        if dist > max_dist {
+            return None;
+        }
+
+        let face = Face::from_normal(trace.normal);
+        let side = cell_side(trace.level);
+        let lo = cell.cast::<f64>();
+        // snap the entry point onto the entered face of the leaf, the f32
+        // traversal can be off by a few hundred voxel units
+        let mut pos = (origin + dir * dist) * VOXELS_PER_UNIT;
+        for i in 0..3 {
+            pos[i] = pos[i].clamp(lo[i], lo[i] + side - 1.0);
+        }
+        if let Some(face) = face {
+            let axis = face.normal().iamax();
+            pos[axis] = lo[axis] + if face.normal()[axis] > 0 { side } else { 0.0 };
+        }
+
+        Some(RayHit {
+            voxel: Voxel {
+                pos: cell,
+                level: trace.level,
+                material,
+            },
+            face,
+            pos,
+            adjacent: face.and_then(|face| adjacent_cell(pos, face, trace.level)),
+            dist,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{Trace, TraceOutcome};
+    use crate::program::voxelbuffer::VoxelBuffer;
+
+    fn cast(world: &VoxelBuffer, origin: [f32; 3], dir: [f32; 3]) -> Trace {
+        world.shader_ray_cast(Vector3::from(origin), Vector3::from(dir))
+    }
+
+    #[test]
+    fn empty_world_misses() {
+        let world = VoxelBuffer::empty();
+        let trace = cast(&world, [0.5, 0.5, 0.5], [1.0, 0.0, 0.0]);
+        assert_eq!(trace.outcome, TraceOutcome::Miss);
+        assert_eq!(trace.dist, 1.5);
+        assert_eq!(trace.pos, Vector3::new(2.0, 0.5, 0.5));
+        assert_eq!(trace.normal, Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!((trace.steps, trace.level), (2, 0));
+    }
+
+    #[test]
+    fn children_are_looked_up_as_x_2y_4z() {
+        // a single root child at x = 1, y = 0, z = 0, octant 1
+        let mut world = VoxelBuffer::empty();
+        world.add_voxel(Vector3::new(1 << 31, 0, 0), 0, 7).unwrap();
+
+        let trace = cast(&world, [0.5, 0.5, 0.5], [1.0, 0.0, 0.0]);
+        assert_eq!(
+            trace.outcome,
+            TraceOutcome::Leaf {
+                material: 7,
+                cell: Vector3::new(1 << 31, 0, 0),
+            }
+        );
+        assert_eq!(trace.dist, 0.5);
+        assert_eq!(trace.normal, Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!((trace.steps, trace.level), (2, 0));
+
+        // octant 4 at x = 0, y = 0, z = 1 is empty
+        let trace = cast(&world, [0.5, 0.5, 0.5], [0.0, 0.0, 1.0]);
+        assert_eq!(trace.outcome, TraceOutcome::Miss);
+        assert_eq!(trace.dist, 1.5);
+    }
+
+    #[test]
+    fn descends_to_deeper_leaves() {
+        // level 1 leaf spanning 1.5..2 x 0..0.5 x 0..0.5
+        let mut world = VoxelBuffer::empty();
+        world.add_voxel(Vector3::new(3 << 30, 0, 0), 1, 2).unwrap();
+
+        let trace = cast(&world, [0.25, 0.25, 0.25], [1.0, 0.0, 0.0]);
+        assert_eq!(
+            trace.outcome,
+            TraceOutcome::Leaf {
+                material: 2,
+                cell: Vector3::new(3 << 30, 0, 0),
+            }
+        );
+        assert_eq!(trace.dist, 1.25);
+        assert_eq!(trace.pos, Vector3::new(1.5, 0.25, 0.25));
+        assert_eq!(trace.normal, Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!((trace.steps, trace.level), (4, 1));
+
+        // passes beside the leaf through the other half of its parent
+        let trace = cast(&world, [0.25, 0.75, 0.25], [1.0, 0.0, 0.0]);
+        assert_eq!(trace.outcome, TraceOutcome::Miss);
+        assert_eq!(trace.dist, 1.75);
+        assert_eq!(trace.level, 0);
+    }
+
+    #[test]
+    fn normal_faces_against_the_ray() {
+        let mut world = VoxelBuffer::empty();
+        world.add_voxel(Vector3::zeros(), 0, 1).unwrap();
+
+        let trace = cast(&world, [0.5, 1.5, 0.5], [0.0, -1.0, 0.0]);
+        assert!(matches!(
+            trace.outcome,
+            TraceOutcome::Leaf { material: 1, .. }
+        ));
+        assert_eq!(trace.dist, 0.5);
+        assert_eq!(trace.normal, Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(trace.steps, 2);
+
+        // starting inside the leaf hits it right away
+        let trace = cast(&world, [0.5, 0.5, 0.5], [0.0, -1.0, 0.0]);
+        assert!(matches!(trace.outcome, TraceOutcome::Leaf { .. }));
+        assert_eq!(trace.dist, 0.0);
+        assert_eq!(trace.normal, Vector3::zeros());
+        assert_eq!(trace.steps, 1);
+    }
+}
@@ -0,0 +1,635 @@
+//! World files: a fixed header, the reachable nodes with every parent before
+//! its children, the palette and a checksum over everything before it. All
+//! numbers are little endian.
+//!
+//! ```text
+//! magic       8 bytes  "VXCWORLD"
+//! version     u32
+//! compression u32      `Compression`, absent in version 1 files
+//! root        u32      index of the root in the node array
+//! root_level  u32
+//! node_count  u32
+//! nodes       node_count nodes, encoded as selected by `compression`
+//! materials   u32      number of palette entries, absent before version 3
+//! palette     materials * 6 f32, colour, emission, roughness, transparency
+//! checksum    u64      FNV-1a of all preceding bytes
+//! ```
+//!
+//! Files without a palette load with the default one.
+//!
+//! Uncompressed nodes are stored as 8 * u32 each. Most children are void, so
+//! the sparse encoding instead writes a byte with one bit per non-void child,
+//! followed by a varint for each of those children:
+//!
+//! - leaf: `material << 1 | 1`
+//! - branch: `(target - node) << 1`, branches always point forward
+//!
+//! Both encodings are decoded straight into the node storage while reading.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    leaf, GrowableBuffer, OctreeNode, VoxelBuffer, INITIAL_NODES, MAX_MATERIAL, MAX_NODES,
+};
+use crate::program::palette::{Material, Palette};
+
+const MAGIC: [u8; 8] = *b"VXCWORLD";
+const VERSION: u32 = 3;
+/// Files of this version have no palette.
+const VERSION_NO_PALETTE: u32 = 2;
+/// Files of this version have no compression field and are uncompressed.
+const VERSION_RAW: u32 = 1;
+
+/// Encoding of the node array in a world file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// eight little endian u32 per node
+    None = 0,
+    /// child masks and varints, see the module documentation
+    #[default]
+    Sparse = 1,
+}
+
+impl Compression {
+    fn from_u32(v: u32) -> Option<Self> {
+        match v {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Sparse),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WorldFileError {
+    Io(io::Error),
+    /// the file does not start with the world file magic
+    NotAWorldFile,
+    UnsupportedVersion(u32),
+    UnsupportedCompression(u32),
+    /// the file ended before all announced nodes were read
+    Truncated,
+    ChecksumMismatch {
+        stored: u64,
+        computed: u64,
+    },
+    /// the checksum matched but the content does not form a valid tree
+    Corrupt(&'static str),
+    /// a branch to be written does not point to a later node, `load` would
+    /// reject the file
+    BackwardBranch {
+        node: usize,
+        target: usize,
+    },
+}
+
+impl std::fmt::Display for WorldFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorldFileError::Io(e) => write!(f, "world file io error: {e}"),
+            WorldFileError::NotAWorldFile => write!(f, "not a world file"),
+            WorldFileError::UnsupportedVersion(v) => {
+                write!(f, "unsupported world file version {v}, expected {VERSION}")
+            }
+            WorldFileError::UnsupportedCompression(c) => {
+                write!(f, "unsupported world file compression {c}")
+            }
+            WorldFileError::Truncated => write!(f, "world file is truncated"),
+            WorldFileError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "world file checksum mismatch, stored {stored:#018x}, computed {computed:#018x}"
+            ),
+            WorldFileError::Corrupt(reason) => write!(f, "world file is corrupt: {reason}"),
+            WorldFileError::BackwardBranch { node, target } => write!(
+                f,
+                "cannot write node {node}, its branch to node {target} does not point forward"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WorldFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WorldFileError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for WorldFileError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            WorldFileError::Truncated
+        } else {
+            WorldFileError::Io(e)
+        }
+    }
+}
+
+/// 64 bit FNV-1a, enough to catch accidental damage.
+struct Checksum(u64);
+
+impl Checksum {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+/// Writer that checksums everything passing through it.
+struct HashWriter<W> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn write_u32(&mut self, v: u32) -> io::Result<()> {
+        self.write_all(&v.to_le_bytes())
+    }
+
+    fn write_f32(&mut self, v: f32) -> io::Result<()> {
+        self.write_all(&v.to_le_bytes())
+    }
+
+    /// LEB128, seven bits per byte with the high bit set on all but the last
+    fn write_varint(&mut self, mut v: u64) -> io::Result<()> {
+        let mut bytes = [0; 10];
+        let mut len = 0;
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                bytes[len] = byte;
+                len += 1;
+                break;
+            }
+            bytes[len] = byte | 0x80;
+            len += 1;
+        }
+        self.write_all(&bytes[..len])
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that checksums everything passing through it.
+struct HashReader<R> {
+    inner: R,
+    checksum: Checksum,
+}
+
+impl<R: Read> HashReader<R> {
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn read_f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    fn read_u8(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_varint(&mut self) -> Result<u64, WorldFileError> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            v |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(WorldFileError::Corrupt("varint too long"))
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl VoxelBuffer {
+    /// Writes the world and its palette to `path` with the default
+    /// compression.
+    pub fn save(&self, path: impl AsRef<Path>, palette: &Palette) -> Result<(), WorldFileError> {
+        self.save_with(path, palette, Compression::default())
+    }
+
+    /// Writes the world and its palette to `path`. Only nodes reachable from
+    /// the root are stored, renumbered so that every branch points to a later
+    /// node.
+    ///
+    /// The file is written next to `path` and then renamed over it, so a
+    /// failed save leaves the previous file intact.
+    pub fn save_with(
+        &self,
+        path: impl AsRef<Path>,
+        palette: &Palette,
+        compression: Compression,
+    ) -> Result<(), WorldFileError> {
+        let path = path.as_ref();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let result = self
+            .write_file(&temp_path, palette, compression)
+            .and_then(|()| Ok(fs::rename(&temp_path, path)?));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        palette: &Palette,
+        compression: Compression,
+    ) -> Result<(), WorldFileError> {
+        let nodes = self.compacted_nodes();
+
+        let mut w = HashWriter {
+            inner: BufWriter::new(File::create(path)?),
+            checksum: Checksum::new(),
+        };
+        w.write_all(&MAGIC)?;
+        w.write_u32(VERSION)?;
+        w.write_u32(compression as u32)?;
+        w.write_u32(0)?;
+        w.write_u32(self.root_level)?;
+        w.write_u32(nodes.len() as u32)?;
+        for (idx, node) in nodes.iter().enumerate() {
+            match compression {
+                Compression::None => {
+                    for child in node.children {
+                        if child & 0b11 == 0b01 {
+                            branch_offset(idx, child)?;
+                        }
+                        w.write_u32(child)?;
+                    }
+                }
+                Compression::Sparse => write_sparse(&mut w, idx, node)?,
+            }
+        }
+        w.write_u32(palette.material_count())?;
+        for id in 0..palette.material_count() {
+            let material = palette.get(id);
+            for v in material.color {
+                w.write_f32(v)?;
+            }
+            w.write_f32(material.emission)?;
+            w.write_f32(material.roughness)?;
+            w.write_f32(material.transparency)?;
+        }
+
+        let checksum = w.checksum.0;
+        let mut inner = w.inner;
+        inner.write_all(&checksum.to_le_bytes())?;
+        inner.flush()?;
+        inner.get_ref().sync_all()?;
+
+        log::info!(
+            "saved world, {} nodes, {} materials, {:?} compression, {} bytes",
+            nodes.len(),
+            palette.material_count(),
+            compression,
+            inner.get_ref().metadata()?.len()
+        );
+        Ok(())
+    }
+
+    /// Reads a world and its palette written by `save`. The file is fully
+    /// validated, a returned buffer is always a well formed tree.
+    pub fn load(path: impl AsRef<Path>) -> Result<(Self, Palette), WorldFileError> {
+        let mut r = HashReader {
+            inner: BufReader::new(File::open(path)?),
+            checksum: Checksum::new(),
+        };
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(WorldFileError::NotAWorldFile);
+        }
+        let version = r.read_u32()?;
+        let compression = match version {
+            VERSION_RAW => Compression::None,
+            VERSION_NO_PALETTE | VERSION => {
+                let c = r.read_u32()?;
+                Compression::from_u32(c).ok_or(WorldFileError::UnsupportedCompression(c))?
+            }
+            _ => return Err(WorldFileError::UnsupportedVersion(version)),
+        };
+        let root = r.read_u32()? as usize;
+        let root_level = r.read_u32()?;
+        let node_count = r.read_u32()? as usize;
+        if node_count == 0 || node_count > MAX_NODES {
+            return Err(WorldFileError::Corrupt("node count out of range"));
+        }
+        if root >= node_count {
+            return Err(WorldFileError::Corrupt("root index out of range"));
+        }
+
+        let mut cpu_buffer = vec![
+            OctreeNode::new();
+            node_count
+                .next_power_of_two()
+                .clamp(INITIAL_NODES, MAX_NODES)
+        ];
+        for (idx, node) in cpu_buffer[..node_count].iter_mut().enumerate() {
+            match compression {
+                Compression::None => {
+                    for child in &mut node.children {
+                        *child = r.read_u32()?;
+                    }
+                }
+                Compression::Sparse => read_sparse(&mut r, idx, node)?,
+            }
+        }
+        let palette = if version == VERSION {
+            read_palette(&mut r)?
+        } else {
+            Palette::new()
+        };
+
+        let computed = r.checksum.0;
+        let mut stored = [0; 8];
+        r.inner.read_exact(&mut stored)?;
+        let stored = u64::from_le_bytes(stored);
+        if stored != computed {
+            return Err(WorldFileError::ChecksumMismatch { stored, computed });
+        }
+        if r.inner.read(&mut [0])? != 0 {
+            return Err(WorldFileError::Corrupt("trailing data after the checksum"));
+        }
+
+        validate_nodes(&cpu_buffer[..node_count], root)?;
+
+        let mut s = Self {
+            gpu_buffer: GrowableBuffer::new("voxel buffer"),
+            cpu_buffer,
+            root,
+            root_level,
+            bump_top: node_count - 1,
+            free_head: 0,
+            free_count: 0,
+            shared: HashMap::new(),
+            dirty: Vec::new(),
+        };
+        s.recount_shared();
+        log::info!(
+            "loaded world, {} nodes, {} materials",
+            node_count,
+            palette.material_count()
+        );
+        Ok((s, palette))
+    }
+
+    /// Reachable nodes with the root first and every parent before its
+    /// children, shared nodes stored once.
+    ///
+    /// This is the reverse of a depth first post-order. Shared nodes have
+    /// several parents, a breadth first order could store one of them after
+    /// the node it points to.
+    fn compacted_nodes(&self) -> Vec<OctreeNode> {
+        let mut visited = HashSet::from([self.root]);
+        let mut order = Vec::new();
+        // nodes on the current path and the next child to look at in each
+        let mut stack = vec![(self.root, 0)];
+        while let Some((idx, next)) = stack.last_mut() {
+            let idx = *idx;
+            let Some(&child) = self.cpu_buffer[idx].children.get(*next) else {
+                order.push(idx);
+                stack.pop();
+                continue;
+            };
+            *next += 1;
+            if child & 0b11 == 0b01 && visited.insert(child as usize >> 2) {
+                stack.push((child as usize >> 2, 0));
+            }
+        }
+        order.reverse();
+        let remap: HashMap<usize, u32> = order
+            .iter()
+            .enumerate()
+            .map(|(new_idx, &idx)| (idx, new_idx as u32))
+            .collect();
+
+        order
+            .iter()
+            .map(|&idx| {
+                let mut node = self.cpu_buffer[idx];
+                for child in &mut node.children {
+                    if *child & 0b11 == 0b01 {
+                        *child = 4 * remap[&(*child as usize >> 2)] + 0b01;
+                    }
+                }
+                node
+            })
+            .collect()
+    }
+}
+
+/// How far the branch `child` of node `idx` points ahead. `load` rejects
+/// branches to earlier nodes, so they are never written.
+fn branch_offset(idx: usize, child: u32) -> Result<usize, WorldFileError> {
+    let target = child as usize >> 2;
+    target
+        .checked_sub(idx)
+        .filter(|&offset| offset > 0)
+        .ok_or(WorldFileError::BackwardBranch { node: idx, target })
+}
+
+fn write_sparse<W: Write>(
+    w: &mut HashWriter<W>,
+    idx: usize,
+    node: &OctreeNode,
+) -> Result<(), WorldFileError> {
+    let mask = node
+        .children
+        .iter()
+        .enumerate()
+        .fold(0_u8, |mask, (i, &child)| mask | u8::from(child != 0) << i);
+    w.write_all(&[mask])?;
+    for child in node.children {
+        match child & 0b11 {
+            0b11 => w.write_varint(u64::from(child >> 2) << 1 | 1)?,
+            0b01 => w.write_varint((branch_offset(idx, child)? as u64) << 1)?,
+            _ => debug_assert_eq!(child, 0, "malformed void child"),
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a node written by `write_sparse`. Branch targets are not checked
+/// here, `validate_nodes` does that for every encoding.
+fn read_sparse<R: Read>(
+    r: &mut HashReader<R>,
+    idx: usize,
+    node: &mut OctreeNode,
+) -> Result<(), WorldFileError> {
+    let mask = r.read_u8()?;
+    for (i, child) in node.children.iter_mut().enumerate() {
+        if mask & (1 << i) == 0 {
+            continue;
+        }
+        let v = r.read_varint()?;
+        *child = if v & 1 == 1 {
+            let material = u32::try_from(v >> 1)
+                .ok()
+                .filter(|&m| m <= MAX_MATERIAL)
+                .ok_or(WorldFileError::Corrupt("material out of range"))?;
+            leaf(material)
+        } else {
+            let target = (v >> 1)
+                .checked_add(idx as u64)
+                .filter(|&t| t < MAX_NODES as u64)
+                .ok_or(WorldFileError::Corrupt("branch index out of order"))?;
+            4 * target as u32 + 0b01
+        };
+    }
+    Ok(())
+}
+
+/// Reads the palette section, every material has to be finite.
+fn read_palette<R: Read>(r: &mut HashReader<R>) -> Result<Palette, WorldFileError> {
+    let count = r.read_u32()?;
+    if count > MAX_MATERIAL + 1 {
+        return Err(WorldFileError::Corrupt("palette too large"));
+    }
+    let mut palette = Palette::new();
+    for id in 0..count {
+        let mut values = [0.0; 6];
+        for v in &mut values {
+            *v = r.read_f32()?;
+        }
+        if !values.iter().all(|v| v.is_finite()) {
+            return Err(WorldFileError::Corrupt("material is not finite"));
+        }
+        let [red, green, blue, emission, roughness, transparency] = values;
+        palette.set(
+            id,
+            Material {
+                color: [red, green, blue],
+                emission,
+                roughness,
+                transparency,
+            },
+        );
+    }
+    Ok(palette)
+}
+
+/// Checks that every child value is well formed and that branches only point
+/// forward, which rules out cycles.
+fn validate_nodes(nodes: &[OctreeNode], root: usize) -> Result<(), WorldFileError> {
+    if root != 0 {
+        // the renderer always starts at node 0
+        return Err(WorldFileError::Corrupt("root is not the first node"));
+    }
+    for (idx, node) in nodes.iter().enumerate() {
+        for child in node.children {
+            match child & 0b11 {
+                0b00 | 0b10 if child != 0 => {
+                    return Err(WorldFileError::Corrupt("void child with payload bits"))
+                }
+                0b01 => {
+                    let target = child as usize >> 2;
+                    if target <= idx || target >= nodes.len() {
+                        return Err(WorldFileError::Corrupt("branch index out of order"));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::Compression;
+    use crate::program::{
+        palette::{Material, Palette},
+        voxelbuffer::VoxelBuffer,
+    };
+
+    fn leaves(world: &VoxelBuffer) -> Vec<(Vector3<u32>, Vector3<u32>, u32)> {
+        let mut leaves = Vec::new();
+        world.for_each_leaf(Vector3::zeros(), Vector3::repeat(u32::MAX), |lo, hi, m| {
+            leaves.push((lo, hi, m));
+        });
+        leaves
+    }
+
+    #[test]
+    fn dag_round_trip() {
+        let mut world = VoxelBuffer::empty();
+        // the node holding the level 1 leaf equals the one holding the level
+        // 2 leaf, after merging it has a parent on two different levels
+        world.add_voxel(Vector3::zeros(), 1, 3).unwrap();
+        world.add_voxel(Vector3::repeat(1 << 31), 2, 3).unwrap();
+        world.add_voxel(Vector3::new(1 << 31, 0, 0), 4, 1).unwrap();
+        world.compress_to_dag();
+        assert!(!world.shared.is_empty());
+        let mut palette = Palette::new();
+        palette.set(
+            6,
+            Material {
+                emission: 0.5,
+                transparency: 0.25,
+                ..Material::new([0.1, 0.2, 0.3])
+            },
+        );
+
+        let dir = std::env::temp_dir().join(format!("voxelcraft-save-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for compression in [Compression::None, Compression::Sparse] {
+            let path = dir.join(format!("{compression:?}.world"));
+            world.save_with(&path, &palette, compression).unwrap();
+            let (loaded, loaded_palette) = VoxelBuffer::load(&path).unwrap();
+            assert_eq!(loaded.node_count(), world.node_count());
+            assert_eq!(leaves(&loaded), leaves(&world));
+            assert_eq!(loaded.shared.len(), world.shared.len());
+            assert_eq!(loaded_palette.material_count(), 7);
+            for id in 0..7 {
+                assert_eq!(loaded_palette.get(id), palette.get(id));
+            }
+        }
+        let left_over: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(left_over.len(), 2, "temporary files were left behind");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}